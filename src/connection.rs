@@ -0,0 +1,550 @@
+use std::{
+    collections::HashMap,
+    io::Result as IoResult,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicI64, AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use salvo::{
+    async_trait,
+    conn::{Accepted, Acceptor, Holding},
+    fuse::{FuseEvent, FuseFactory, FuseInfo, Fusewire},
+    http::{header, HeaderValue, Version},
+    prelude::*,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+/// Limits enforced by [`ConnectionGuard`], `None` meaning unlimited.
+pub(crate) struct ConnectionLimits {
+    pub(crate) max_per_ip: Option<u32>,
+    pub(crate) max_total: Option<u32>,
+    pub(crate) slow_client_timeout: Option<Duration>,
+    pub(crate) keepalive_timeout: Option<Duration>,
+    pub(crate) keepalive_max_requests: Option<u32>,
+}
+
+#[derive(Default)]
+struct Counters {
+    total: AtomicU32,
+    per_ip: Mutex<HashMap<IpAddr, u32>>,
+    /// Connections whose next request hasn't fully arrived yet, by remote address.
+    awaiting_request: Mutex<HashMap<SocketAddr, CancellationToken>>,
+    /// Keep-alive bookkeeping of every open connection, by remote address.
+    keep_alive: Mutex<HashMap<SocketAddr, Arc<KeepAlive>>>,
+}
+
+/// Tracks whether a connection is idle and how many requests it has served.
+struct KeepAlive {
+    opened: Instant,
+    /// Milliseconds after `opened` at which data was last read or written.
+    last_io: AtomicU64,
+    in_flight: AtomicU32,
+    served: AtomicU32,
+    /// Set once an HTTP/2 connection has served its last request.
+    draining: AtomicBool,
+    /// Set by the first request, connections speak HTTP/2 from the start or not at all.
+    http2: AtomicBool,
+    fuse: CancellationToken,
+}
+
+/// How long an HTTP/2 connection that served its last request has to be quiet before it's
+/// closed. Salvo has no way to send a GOAWAY, so this keeps responses still being written
+/// from being cut off.
+const DRAIN_GRACE: Duration = Duration::from_secs(1);
+
+impl KeepAlive {
+    fn touch(&self) {
+        let elapsed = self.opened.elapsed().as_millis() as u64;
+        self.last_io.store(elapsed, Ordering::Relaxed);
+    }
+
+    fn idle_for(&self) -> Duration {
+        let last_io = Duration::from_millis(self.last_io.load(Ordering::Relaxed));
+        self.opened.elapsed().saturating_sub(last_io)
+    }
+}
+
+/// Limits concurrent connections, globally and per client IP, drops clients that are too
+/// slow to send a request and closes keep-alive connections that sit idle or have
+/// served enough requests.
+///
+/// Salvo asks the factory for a fusewire right after accepting a connection and closes the
+/// connection as soon as that wire reports being fused, so over-limit connections are dropped
+/// before any HTTP is parsed. Used as a hoop as well, it learns when a connection's requests
+/// start and end.
+#[derive(Clone)]
+pub(crate) struct ConnectionGuard {
+    limits: Arc<ConnectionLimits>,
+    counters: Arc<Counters>,
+}
+
+impl ConnectionGuard {
+    pub(crate) fn new(limits: ConnectionLimits) -> Self {
+        ConnectionGuard {
+            limits: Arc::new(limits),
+            counters: Arc::default(),
+        }
+    }
+
+    /// Registers a new connection, returning `false` when it exceeds a limit.
+    fn admit(&self, ip: Option<IpAddr>) -> bool {
+        let total = self.counters.total.load(Ordering::Relaxed);
+        if self.limits.max_total.is_some_and(|max| total >= max) {
+            let ip = ip.map_or("unknown address".to_string(), |d| d.to_string());
+            warn!("Rejected connection from {ip}, {total} connections open in total");
+            return false;
+        }
+        if let Some(ip) = ip {
+            let mut per_ip = self.counters.per_ip.lock().unwrap();
+            let count = per_ip.entry(ip).or_default();
+            if self.limits.max_per_ip.is_some_and(|max| *count >= max) {
+                warn!("Rejected connection from {ip}, {count} connections already open");
+                return false;
+            }
+            *count += 1;
+        }
+        self.counters.total.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// Fuses the connection once it has neither a request in flight nor any I/O for `timeout`.
+    fn watch_idle(keep_alive: Arc<KeepAlive>, timeout: Duration, fuse: CancellationToken) {
+        tokio::spawn(async move {
+            let mut wait = timeout;
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(wait) => {}
+                    _ = fuse.cancelled() => return,
+                }
+                let idle_for = keep_alive.idle_for();
+                if keep_alive.in_flight.load(Ordering::Relaxed) > 0 {
+                    wait = timeout;
+                } else if idle_for >= timeout {
+                    debug!("Closing connection idle for {}s", idle_for.as_secs());
+                    fuse.cancel();
+                    return;
+                } else {
+                    wait = timeout - idle_for;
+                }
+            }
+        });
+    }
+}
+
+/// Fuses the connection unless the hoop sees its next request within the timeout.
+fn watch_request(
+    counters: &Arc<Counters>,
+    addr: SocketAddr,
+    timeout: Duration,
+    fuse: CancellationToken,
+) {
+    let received = CancellationToken::new();
+    counters
+        .awaiting_request
+        .lock()
+        .unwrap()
+        .insert(addr, received.clone());
+    let counters = counters.clone();
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = tokio::time::sleep(timeout) => {
+                info!("Dropping slow client {}, no request within {}ms", addr.ip(), timeout.as_millis());
+                counters.awaiting_request.lock().unwrap().remove(&addr);
+                fuse.cancel();
+            }
+            _ = received.cancelled() => {}
+        }
+    });
+}
+
+impl FuseFactory for ConnectionGuard {
+    fn create(&self, info: FuseInfo) -> Arc<dyn Fusewire + Sync + Send + 'static> {
+        let addr = info.remote_addr.into_std();
+        let admitted = self.admit(addr.map(|d| d.ip()));
+        let fuse = CancellationToken::new();
+        let mut keep_alive = None;
+        if let (true, Some(addr)) = (admitted, addr) {
+            if let Some(timeout) = self.limits.slow_client_timeout {
+                watch_request(&self.counters, addr, timeout, fuse.clone());
+            }
+            let state = Arc::new(KeepAlive {
+                opened: Instant::now(),
+                last_io: AtomicU64::new(0),
+                in_flight: AtomicU32::new(0),
+                served: AtomicU32::new(0),
+                draining: AtomicBool::new(false),
+                http2: AtomicBool::new(false),
+                fuse: fuse.clone(),
+            });
+            if let Some(timeout) = self.limits.keepalive_timeout {
+                Self::watch_idle(state.clone(), timeout, fuse.clone());
+            }
+            self.counters
+                .keep_alive
+                .lock()
+                .unwrap()
+                .insert(addr, state.clone());
+            keep_alive = Some(state);
+        }
+        Arc::new(ConnectionWire {
+            addr,
+            admitted,
+            fuse,
+            keep_alive,
+            slow_client_timeout: self.limits.slow_client_timeout,
+            counters: self.counters.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl Handler for ConnectionGuard {
+    async fn handle(
+        &self,
+        req: &mut Request,
+        depot: &mut Depot,
+        res: &mut Response,
+        ctrl: &mut FlowCtrl,
+    ) {
+        let Some(addr) = req.remote_addr().clone().into_std() else {
+            return;
+        };
+        if let Some(received) = self.counters.awaiting_request.lock().unwrap().remove(&addr) {
+            received.cancel();
+        }
+        let keep_alive = self.counters.keep_alive.lock().unwrap().get(&addr).cloned();
+        let Some(keep_alive) = keep_alive else {
+            return;
+        };
+        keep_alive
+            .http2
+            .store(req.version() == Version::HTTP_2, Ordering::Relaxed);
+        keep_alive.in_flight.fetch_add(1, Ordering::Relaxed);
+        let served = keep_alive.served.fetch_add(1, Ordering::Relaxed) + 1;
+        ctrl.call_next(req, depot, res).await;
+        keep_alive.in_flight.fetch_sub(1, Ordering::Relaxed);
+        keep_alive.touch();
+        if self
+            .limits
+            .keepalive_max_requests
+            .is_none_or(|max| served < max)
+        {
+            return;
+        }
+        if req.version() < Version::HTTP_2 {
+            // Hyper closes the connection after sending a response marked like this
+            res.headers_mut()
+                .insert(header::CONNECTION, HeaderValue::from_static("close"));
+        } else if !keep_alive.draining.swap(true, Ordering::Relaxed) {
+            // Connection-specific headers are forbidden in HTTP/2 (RFC 9113 8.2.2)
+            let fuse = keep_alive.fuse.clone();
+            Self::watch_idle(keep_alive, DRAIN_GRACE, fuse);
+        }
+    }
+}
+
+/// Lives as long as its connection and releases the connection's slot when dropped.
+struct ConnectionWire {
+    addr: Option<SocketAddr>,
+    admitted: bool,
+    fuse: CancellationToken,
+    keep_alive: Option<Arc<KeepAlive>>,
+    slow_client_timeout: Option<Duration>,
+    counters: Arc<Counters>,
+}
+
+#[async_trait]
+impl Fusewire for ConnectionWire {
+    fn event(&self, event: FuseEvent) {
+        let Some(keep_alive) = &self.keep_alive else {
+            return;
+        };
+        if !matches!(event, FuseEvent::ReadData(_) | FuseEvent::WriteData(_)) {
+            return;
+        }
+        keep_alive.touch();
+        // Data arriving between requests starts the next one, which gets the same time to
+        // arrive as the first. Otherwise a keep-alive connection could trickle in headers.
+        // HTTP/2 clients also send frames like PING between requests, so it's HTTP/1 only.
+        if let (FuseEvent::ReadData(_), Some(timeout), Some(addr)) =
+            (event, self.slow_client_timeout, self.addr)
+        {
+            let between_requests = !keep_alive.http2.load(Ordering::Relaxed)
+                && keep_alive.served.load(Ordering::Relaxed) > 0
+                && keep_alive.in_flight.load(Ordering::Relaxed) == 0;
+            if between_requests
+                && !self
+                    .counters
+                    .awaiting_request
+                    .lock()
+                    .unwrap()
+                    .contains_key(&addr)
+            {
+                watch_request(&self.counters, addr, timeout, self.fuse.clone());
+            }
+        }
+    }
+
+    async fn fused(&self) {
+        if self.admitted {
+            self.fuse.cancelled().await;
+        }
+    }
+}
+
+impl Drop for ConnectionWire {
+    fn drop(&mut self) {
+        if !self.admitted {
+            return;
+        }
+        // Ends the idle watch
+        self.fuse.cancel();
+        self.counters.total.fetch_sub(1, Ordering::Relaxed);
+        let Some(addr) = self.addr else {
+            return;
+        };
+        // Ends the slow client watch of connections closed before sending anything
+        if let Some(received) = self.counters.awaiting_request.lock().unwrap().remove(&addr) {
+            received.cancel();
+        }
+        self.counters.keep_alive.lock().unwrap().remove(&addr);
+        let mut per_ip = self.counters.per_ip.lock().unwrap();
+        if let Some(count) = per_ip.get_mut(&addr.ip()) {
+            *count -= 1;
+            if *count == 0 {
+                per_ip.remove(&addr.ip());
+            }
+        }
+    }
+}
+
+/// Number of requests currently being handled, maintained by running it as a hoop.
+#[derive(Clone, Default)]
+pub(crate) struct QueueDepth(Arc<AtomicI64>);
+
+/// Decrements the depth even if the request handling future is dropped halfway.
+struct DepthGuard(Arc<AtomicI64>);
+
+impl Drop for DepthGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[async_trait]
+impl Handler for QueueDepth {
+    async fn handle(
+        &self,
+        req: &mut Request,
+        depot: &mut Depot,
+        res: &mut Response,
+        ctrl: &mut FlowCtrl,
+    ) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        let _guard = DepthGuard(self.0.clone());
+        ctrl.call_next(req, depot, res).await;
+    }
+}
+
+/// Stops accepting connections while more than `max_depth` requests are in flight.
+pub(crate) struct BackpressureAcceptor<A> {
+    inner: A,
+    depth: QueueDepth,
+    max_depth: Option<i64>,
+    pause: Duration,
+}
+
+impl<A> BackpressureAcceptor<A> {
+    pub(crate) fn new(
+        inner: A,
+        depth: QueueDepth,
+        max_depth: Option<i64>,
+        pause: Duration,
+    ) -> Self {
+        BackpressureAcceptor {
+            inner,
+            depth,
+            max_depth,
+            pause,
+        }
+    }
+}
+
+impl<A: Acceptor + Send> Acceptor for BackpressureAcceptor<A> {
+    type Conn = A::Conn;
+
+    fn holdings(&self) -> &[Holding] {
+        self.inner.holdings()
+    }
+
+    async fn accept(
+        &mut self,
+        fuse_factory: Option<Arc<dyn FuseFactory + Sync + Send + 'static>>,
+    ) -> IoResult<Accepted<Self::Conn>> {
+        if let Some(max_depth) = self.max_depth {
+            let mut paused = false;
+            loop {
+                let depth = self.depth.0.load(Ordering::Relaxed);
+                if depth <= max_depth {
+                    break;
+                }
+                if !paused {
+                    warn!("{depth} requests in flight, pausing accepting connections");
+                    paused = true;
+                }
+                tokio::time::sleep(self.pause).await;
+            }
+        }
+        self.inner.accept(fuse_factory).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use salvo::conn::TcpListener;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        time::{sleep, timeout},
+    };
+
+    use super::*;
+
+    #[handler]
+    async fn ok(res: &mut Response) {
+        res.render("ok");
+    }
+
+    /// Serves `ok` behind a guard with a 300ms slow client timeout and no idle timeout.
+    async fn serve(keepalive_max_requests: Option<u32>) -> SocketAddr {
+        let acceptor = TcpListener::new("127.0.0.1:0").bind().await;
+        let addr = acceptor.holdings()[0]
+            .local_addr
+            .clone()
+            .into_std()
+            .unwrap();
+        let guard = ConnectionGuard::new(ConnectionLimits {
+            max_per_ip: None,
+            max_total: None,
+            slow_client_timeout: Some(Duration::from_millis(300)),
+            keepalive_timeout: None,
+            keepalive_max_requests,
+        });
+        let service = Service::new(Router::new().get(ok)).hoop(guard.clone());
+        let server = Server::new(acceptor).fuse_factory(guard);
+        tokio::spawn(server.serve(service));
+        addr
+    }
+
+    /// Reads until the peer closes the connection or `ok` arrives, returning what was read.
+    async fn read_response(stream: &mut TcpStream) -> String {
+        let mut read = vec![];
+        let mut buf = [0; 1024];
+        while !read.ends_with(b"ok") {
+            match stream.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => read.extend_from_slice(&buf[..n]),
+            }
+        }
+        String::from_utf8_lossy(&read).into_owned()
+    }
+
+    #[tokio::test]
+    async fn drops_trickled_keep_alive_request() {
+        let addr = serve(None).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n")
+            .await
+            .unwrap();
+        assert!(read_response(&mut stream).await.starts_with("HTTP/1.1 200"));
+        // The second request takes far longer than the timeout to arrive
+        let trickle = async {
+            for byte in b"GET / HTTP/1.1\r\nHost: a\r\n\r\n" {
+                if stream.write_all(&[*byte]).await.is_err() {
+                    break;
+                }
+                sleep(Duration::from_millis(100)).await;
+            }
+            read_response(&mut stream).await
+        };
+        let response = timeout(Duration::from_secs(5), trickle).await.unwrap();
+        assert!(!response.starts_with("HTTP/1.1 200"));
+    }
+
+    #[tokio::test]
+    async fn keeps_idle_keep_alive_connection() {
+        let addr = serve(None).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        for _ in 0..2 {
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n")
+                .await
+                .unwrap();
+            assert!(read_response(&mut stream).await.starts_with("HTTP/1.1 200"));
+            // Idle for longer than the slow client timeout, which only runs mid-request
+            sleep(Duration::from_millis(500)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_idle_http2_connection() {
+        let addr = serve(None).await;
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut client, connection) = h2::client::handshake(stream).await.unwrap();
+        tokio::spawn(connection);
+        for _ in 0..2 {
+            let request = salvo::hyper::Request::get(format!("http://{addr}/"))
+                .body(())
+                .unwrap();
+            let (response, _) = client.send_request(request, true).unwrap();
+            assert_eq!(response.await.unwrap().status(), StatusCode::OK);
+            // Frames sent after the response mustn't start the slow client timeout
+            sleep(Duration::from_millis(500)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn closes_http1_connection_after_max_requests() {
+        let addr = serve(Some(1)).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n")
+            .await
+            .unwrap();
+        let response = read_response(&mut stream).await.to_lowercase();
+        assert!(response.contains("\r\nconnection: close\r\n"));
+        let mut buf = [0; 16];
+        let closed = timeout(Duration::from_secs(1), stream.read(&mut buf)).await;
+        assert!(matches!(closed, Ok(Ok(0)) | Ok(Err(_))));
+    }
+
+    #[tokio::test]
+    async fn closes_http2_connection_after_max_requests() {
+        let addr = serve(Some(1)).await;
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut client, connection) = h2::client::handshake(stream).await.unwrap();
+        let connection = tokio::spawn(connection);
+        let request = salvo::hyper::Request::get(format!("http://{addr}/"))
+            .body(())
+            .unwrap();
+        let (response, _) = client.send_request(request, true).unwrap();
+        let response = response.await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!response.headers().contains_key(header::CONNECTION));
+        // `client` is still alive, so only the server can end the connection
+        timeout(DRAIN_GRACE * 3, connection)
+            .await
+            .unwrap()
+            .unwrap()
+            .ok();
+        drop(client);
+    }
+}
@@ -0,0 +1,127 @@
+use std::{env, fs};
+
+use tracing::info;
+
+use crate::{ParseError, ENV_PREFIX};
+
+/// Parses one `KEY=VALUE` line, `None` meaning it isn't one.
+///
+/// Values may be wrapped in double quotes, where `\n`, `\"` and `\\` are unescaped, or in
+/// single quotes, taken literally. Unquoted values end at a ` #` comment.
+fn parse_line(line: &str) -> Option<(String, String)> {
+    let line = line.strip_prefix("export ").unwrap_or(line);
+    let (key, value) = line.split_once('=')?;
+    let key = key.trim();
+    if key.is_empty() || !key.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_') {
+        return None;
+    }
+    let value = value.trim();
+    let value = if let Some(quoted) = value.strip_prefix('"') {
+        let mut unescaped = String::new();
+        let mut chars = quoted.chars();
+        loop {
+            match chars.next()? {
+                '"' => break,
+                '\\' => match chars.next()? {
+                    'n' => unescaped.push('\n'),
+                    d => unescaped.push(d),
+                },
+                d => unescaped.push(d),
+            }
+        }
+        unescaped
+    } else if let Some(quoted) = value.strip_prefix('\'') {
+        quoted.split_once('\'')?.0.to_string()
+    } else {
+        value
+            .split(" #")
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_string()
+    };
+    Some((key.to_string(), value))
+}
+
+/// Loads the file given by `--env-file` or `SR_REDIR__ENV_FILE` into the environment.
+///
+/// Variables that are already set win over the file, as with Docker's `--env-file`. Returns
+/// whether a file was loaded.
+pub(crate) fn load() -> Result<bool, ParseError> {
+    let key = format!("{ENV_PREFIX}__ENV_FILE");
+    let mut args = env::args().skip(1);
+    let mut path = None;
+    while let Some(arg) = args.next() {
+        if arg == "--env-file" {
+            let Some(d) = args.next() else {
+                return Err(ParseError::EnvFile(
+                    "--env-file".to_string(),
+                    "no path given".to_string(),
+                ));
+            };
+            path = Some(d);
+        } else if let Some(d) = arg.strip_prefix("--env-file=") {
+            path = Some(d.to_string());
+        }
+    }
+    let Some(path) = path.or_else(|| env::var(&key).ok()) else {
+        return Ok(false);
+    };
+    let content =
+        fs::read_to_string(&path).map_err(|e| ParseError::EnvFile(path.clone(), e.to_string()))?;
+    let mut loaded = 0;
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((key, value)) = parse_line(line) else {
+            return Err(ParseError::EnvFile(
+                path,
+                format!("line {} isn't KEY=VALUE", i + 1),
+            ));
+        };
+        if env::var_os(&key).is_none() {
+            env::set_var(key, value);
+            loaded += 1;
+        }
+    }
+    info!("Loaded {loaded} variables from {path}");
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair(key: &str, value: &str) -> Option<(String, String)> {
+        Some((key.to_string(), value.to_string()))
+    }
+
+    #[test]
+    fn plain_values() {
+        assert_eq!(parse_line("A=b"), pair("A", "b"));
+        assert_eq!(parse_line("export A = b "), pair("A", "b"));
+        assert_eq!(parse_line("A="), pair("A", ""));
+        assert_eq!(parse_line("A=b=c"), pair("A", "b=c"));
+        assert_eq!(parse_line("A=b # comment"), pair("A", "b"));
+        assert_eq!(parse_line("A=b#c"), pair("A", "b#c"));
+    }
+
+    #[test]
+    fn quoted_values() {
+        assert_eq!(parse_line(r#"A="b # c""#), pair("A", "b # c"));
+        assert_eq!(parse_line(r#"A="a\nb\"c\\""#), pair("A", "a\nb\"c\\"));
+        assert_eq!(parse_line(r#"A='a\nb' # comment"#), pair("A", r"a\nb"));
+        assert_eq!(parse_line(r#"A="unterminated"#), None);
+        assert_eq!(parse_line("A='unterminated"), None);
+    }
+
+    #[test]
+    fn rejects_invalid_keys() {
+        assert_eq!(parse_line("no equals sign"), None);
+        assert_eq!(parse_line("=b"), None);
+        assert_eq!(parse_line("A-B=c"), None);
+        assert_eq!(parse_line("A B=c"), None);
+    }
+}
@@ -0,0 +1,204 @@
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+};
+
+use tracing::info;
+
+use crate::{ParseError, ENV_PREFIX};
+
+/// Fields that belong to the handler itself and are never copied from a base.
+const NOT_INHERITED: [&str; 2] = ["INHERITS", "ABSTRACT"];
+
+/// Names of all handlers that have a `__INHERITS` variable, sorted so errors are reproducible.
+fn inheriting_names(vars: &HashMap<String, String>) -> Vec<String> {
+    let prefix = format!("{ENV_PREFIX}_");
+    let mut names: Vec<String> = vars
+        .keys()
+        .filter_map(|key| {
+            let name = key.strip_prefix(&prefix)?.strip_suffix("__INHERITS")?;
+            (!name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric()))
+                .then(|| name.to_string())
+        })
+        .collect();
+    names.sort();
+    names
+}
+
+/// Copies every `SR_REDIR_<base>__FIELD` that `name` doesn't set itself, after doing the same
+/// for the base first.
+fn resolve(
+    name: &str,
+    vars: &mut HashMap<String, String>,
+    resolved: &mut HashSet<String>,
+    visiting: &mut Vec<String>,
+) -> Result<(), ParseError> {
+    if resolved.contains(name) {
+        return Ok(());
+    }
+    if visiting.iter().any(|d| d == name) {
+        visiting.push(name.to_string());
+        return Err(ParseError::InheritanceCycle(visiting.join(" → ")));
+    }
+    let inherits_key = format!("{ENV_PREFIX}_{name}__INHERITS");
+    let Some(base) = vars.get(&inherits_key).cloned() else {
+        resolved.insert(name.to_string());
+        return Ok(());
+    };
+    visiting.push(name.to_string());
+    resolve(&base, vars, resolved, visiting)?;
+    visiting.pop();
+    let base_prefix = format!("{ENV_PREFIX}_{base}__");
+    let fields: Vec<(String, String)> = vars
+        .iter()
+        .filter_map(|(key, value)| {
+            Some((key.strip_prefix(&base_prefix)?.to_string(), value.clone()))
+        })
+        .filter(|(field, _)| !NOT_INHERITED.contains(&field.as_str()))
+        .collect();
+    if fields.is_empty() {
+        return Err(ParseError::Missing(format!("{base_prefix}*")));
+    }
+    // The base's hash is for the base's target, it can't match one the handler sets itself
+    let own_target = vars.contains_key(&format!("{ENV_PREFIX}_{name}__TARGET"));
+    for (field, value) in fields {
+        if own_target && field == "TARGET_HASH" {
+            continue;
+        }
+        vars.entry(format!("{ENV_PREFIX}_{name}__{field}"))
+            .or_insert(value);
+    }
+    info!("Handler {name} inherits from {base}");
+    resolved.insert(name.to_string());
+    Ok(())
+}
+
+/// Fills in the fields every inheriting handler gets from its bases.
+fn resolve_all(vars: &mut HashMap<String, String>) -> Result<(), ParseError> {
+    let mut resolved = HashSet::new();
+    for name in inheriting_names(vars) {
+        resolve(&name, vars, &mut resolved, &mut vec![])?;
+    }
+    Ok(())
+}
+
+/// Applies `SR_REDIR_<NAME>__INHERITS=<base>` by filling in the environment.
+///
+/// Handlers then parse as if they had all of their base's fields set themselves. This writes
+/// to the environment, so it has to run before any other thread is started.
+pub(crate) fn apply() -> Result<(), ParseError> {
+    let mut vars: HashMap<String, String> = env::vars().collect();
+    resolve_all(&mut vars)?;
+    for (key, value) in vars {
+        if env::var_os(&key).is_none() {
+            env::set_var(key, value);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (format!("{ENV_PREFIX}_{key}"), value.to_string()))
+            .collect()
+    }
+
+    fn get<'a>(vars: &'a HashMap<String, String>, key: &str) -> Option<&'a str> {
+        vars.get(&format!("{ENV_PREFIX}_{key}")).map(String::as_str)
+    }
+
+    #[test]
+    fn inherits_over_a_chain() {
+        let mut vars = vars(&[
+            ("A__INHERITS", "B"),
+            ("B__INHERITS", "C"),
+            ("B__CODE", "307"),
+            ("C__CODE", "301"),
+            ("C__TARGET", "https://c.example"),
+            ("C__JS_ONLY", "true"),
+        ]);
+        resolve_all(&mut vars).unwrap();
+        assert_eq!(get(&vars, "A__CODE"), Some("307"));
+        assert_eq!(get(&vars, "A__TARGET"), Some("https://c.example"));
+        assert_eq!(get(&vars, "A__JS_ONLY"), Some("true"));
+        assert_eq!(get(&vars, "B__TARGET"), Some("https://c.example"));
+        assert_eq!(get(&vars, "C__CODE"), Some("301"));
+    }
+
+    #[test]
+    fn own_values_win() {
+        let mut vars = vars(&[
+            ("A__INHERITS", "B"),
+            ("A__CODE", "302"),
+            ("B__CODE", "301"),
+            ("B__PRESERVE_PARAMS", "true"),
+        ]);
+        resolve_all(&mut vars).unwrap();
+        assert_eq!(get(&vars, "A__CODE"), Some("302"));
+        assert_eq!(get(&vars, "A__PRESERVE_PARAMS"), Some("true"));
+    }
+
+    #[test]
+    fn handler_fields_stay_with_the_base() {
+        let mut vars = vars(&[
+            ("A__INHERITS", "B"),
+            ("B", "/b"),
+            ("B__ABSTRACT", "true"),
+            ("B__INHERITS", "C"),
+            ("B__CODE", "301"),
+            ("C__CODE", "308"),
+        ]);
+        resolve_all(&mut vars).unwrap();
+        assert_eq!(get(&vars, "A__INHERITS"), Some("B"));
+        assert_eq!(get(&vars, "A__ABSTRACT"), None);
+        assert_eq!(get(&vars, "A"), None);
+        assert_eq!(get(&vars, "A__CODE"), Some("301"));
+    }
+
+    #[test]
+    fn target_hash_needs_the_inherited_target() {
+        let base = [
+            ("B__TARGET", "https://b.example"),
+            ("B__TARGET_HASH", "sha256:abc"),
+        ];
+        let mut inheriting = vars(&[&base[..], &[("A__INHERITS", "B")]].concat());
+        resolve_all(&mut inheriting).unwrap();
+        assert_eq!(get(&inheriting, "A__TARGET_HASH"), Some("sha256:abc"));
+        let mut overriding = vars(
+            &[
+                &base[..],
+                &[("A__INHERITS", "B"), ("A__TARGET", "https://a.example")],
+            ]
+            .concat(),
+        );
+        resolve_all(&mut overriding).unwrap();
+        assert_eq!(get(&overriding, "A__TARGET"), Some("https://a.example"));
+        assert_eq!(get(&overriding, "A__TARGET_HASH"), None);
+    }
+
+    #[test]
+    fn reports_cycles() {
+        let mut vars = vars(&[
+            ("A__INHERITS", "B"),
+            ("B__INHERITS", "C"),
+            ("C__INHERITS", "A"),
+        ]);
+        let e = resolve_all(&mut vars).unwrap_err();
+        assert_eq!(e.to_string(), "Handlers inherit in a cycle: A → B → C → A");
+    }
+
+    #[test]
+    fn reports_missing_base() {
+        let mut vars = vars(&[("A__INHERITS", "NOPE"), ("A__CODE", "302")]);
+        let e = resolve_all(&mut vars).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            format!("Variable \"{ENV_PREFIX}_NOPE__*\" is missing")
+        );
+    }
+}
@@ -1,43 +1,462 @@
-use std::{env, sync::RwLock};
+use std::{
+    collections::HashMap,
+    env, fmt, io,
+    path::PathBuf,
+    process,
+    str::FromStr,
+    sync::{Arc, LazyLock, Mutex, RwLock},
+    time::{Duration, Instant},
+};
 
+mod connection;
+mod envfile;
+mod inherit;
+mod middleware;
+#[cfg(all(unix, feature = "unix-privdrop"))]
+mod privdrop;
+mod proxy;
+mod rewrite;
+
+use connection::{BackpressureAcceptor, ConnectionGuard, ConnectionLimits, QueueDepth};
+use futures_util::stream;
+use middleware::{AccessLog, RequestBodyLimit, RequestTraceMiddleware};
+use proxy::ProxyOptions;
 use regex::Regex;
-use salvo::{http::HeaderMap, prelude::*};
-use tracing::{error, info};
+use rewrite::PathRewrite;
+use salvo::{
+    fs::NamedFile,
+    http::{
+        header::{ACCEPT, ACCEPT_RANGES, IF_RANGE, RANGE, VARY},
+        mime, HeaderMap, HeaderName, HeaderValue, Mime,
+    },
+    hyper::body::Bytes,
+    prelude::*,
+};
+use sha2::{Digest, Sha256};
+use tokio::runtime::{self, Runtime};
+use tracing::{error, info, warn, Level};
+use url::Url;
 
 const ENV_PREFIX: &str = "SR_REDIR";
 
+static NAME_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(&format!(r"^{ENV_PREFIX}_([a-zA-Z0-9]+)$")).unwrap());
+
+const MAX_CONDITIONS: usize = 5;
+
+const REDIRECT_COUNT_HEADER: &str = "X-Redirect-Count";
+
 const REDIRECT_HTML_PAGE: &str = r#"<!DOCTYPE html><html><head><meta http-equiv="refresh" content="0;url={REDIRECT_URL}"><title>Redirecting...</title></head><body><p>If you are not redirected, <a href="{REDIRECT_URL}">click here</a>.</p></body></html>"#;
 
+/// Put first into the `<head>` of the redirect page to drop the query string from the address
+/// bar before the refresh fires.
+const STRIP_QUERY_SCRIPT: &str =
+    r#"<script>history.replaceState(null, "", location.pathname + location.hash)</script>"#;
+
 #[derive(Debug)]
 enum ParseError {
     Missing(String),
     WrongFormat(String, String),
+    HashMismatch(String),
+    DuplicateName(String),
+    EnvFile(String, String),
+    InheritanceCycle(String),
+    PrivilegeDrop(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Missing(d) => write!(f, "Variable \"{d}\" is missing"),
+            Self::WrongFormat(key, expected_type) => {
+                write!(
+                    f,
+                    "Variable \"{key}\" has wrong type, expected {expected_type}"
+                )
+            }
+            Self::HashMismatch(key) => {
+                write!(f, "Variable \"{key}\" doesn't match the computed hash")
+            }
+            Self::DuplicateName(name) => write!(f, "Handler \"{name}\" is defined more than once"),
+            Self::EnvFile(path, e) => write!(f, "Env file \"{path}\" can't be loaded: {e}"),
+            Self::InheritanceCycle(chain) => write!(f, "Handlers inherit in a cycle: {chain}"),
+            Self::PrivilegeDrop(e) => write!(f, "Can't drop privileges: {e}"),
+        }
+    }
 }
 
 impl ParseError {
     fn unpack(self) {
-        match self {
-            Self::Missing(d) => {
-                error!("Variable \"{d}\" is missing! Exiting.");
+        // Inside GitHub Actions, emit a workflow command so the error shows up as an annotation
+        if env::var("GITHUB_ACTIONS").is_ok_and(|d| d == "true") {
+            // Workflow commands end at a newline, so it and the escape character itself are encoded
+            let message = self
+                .to_string()
+                .replace('%', "%25")
+                .replace('\r', "%0D")
+                .replace('\n', "%0A");
+            println!("::error file=.env,line=1,col=1,title=Config Error::{message}");
+        } else {
+            error!("{self}! Exiting.");
+        }
+    }
+}
+
+/// Reads a numeric variable, falling back to `default` when it isn't set.
+fn num_var<T: FromStr>(key: String, default: T) -> Result<T, ParseError> {
+    Ok(opt_num_var(key)?.unwrap_or(default))
+}
+
+/// Reads a numeric variable, returning `None` when it isn't set.
+fn opt_num_var<T: FromStr>(key: String) -> Result<Option<T>, ParseError> {
+    match env::var(&key) {
+        Ok(d) => match d.parse::<T>() {
+            Ok(d) => Ok(Some(d)),
+            Err(_) => Err(ParseError::WrongFormat(key, "Integer".to_string())),
+        },
+        Err(_) => Ok(None),
+    }
+}
+
+/// Parses a byte count like `512`, `10KB` or `1MiB`.
+fn parse_bytes(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let multiplier: u64 = match unit.trim().to_uppercase().as_str() {
+        "" | "B" => 1,
+        "KB" => 1_000,
+        "MB" => 1_000_000,
+        "GB" => 1_000_000_000,
+        "KIB" => 1 << 10,
+        "MIB" => 1 << 20,
+        "GIB" => 1 << 30,
+        _ => return None,
+    };
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+/// Reads a byte count (see [`parse_bytes`]), returning `None` when it isn't set.
+fn byte_var(key: String) -> Result<Option<u64>, ParseError> {
+    let Ok(d) = env::var(&key) else {
+        return Ok(None);
+    };
+    match parse_bytes(&d) {
+        Some(d) => Ok(Some(d)),
+        None => Err(ParseError::WrongFormat(key, "byte count".to_string())),
+    }
+}
+
+/// Reads a fraction between 0 and 1, returning `None` when it isn't set.
+fn rate_var(key: String) -> Result<Option<f32>, ParseError> {
+    match env::var(&key) {
+        Ok(d) => match d.parse::<f32>() {
+            Ok(d) if (0.0..=1.0).contains(&d) => Ok(Some(d)),
+            _ => Err(ParseError::WrongFormat(
+                key,
+                "number between 0 and 1".to_string(),
+            )),
+        },
+        Err(_) => Ok(None),
+    }
+}
+
+/// Reads a boolean variable, defaulting to `false` when it isn't set.
+fn bool_var(key: String) -> Result<bool, ParseError> {
+    match env::var(&key) {
+        Ok(d) => match d.to_lowercase().parse::<bool>() {
+            Ok(d) => Ok(d),
+            Err(_) => Err(ParseError::WrongFormat(key, "Boolean".to_string())),
+        },
+        Err(_) => Ok(false),
+    }
+}
+
+fn sha256_hex(data: &str) -> String {
+    format!("{:x}", Sha256::digest(data.as_bytes()))
+}
+
+/// Parses a `sha256:<hex>` value into its lowercase hex digest.
+fn parse_sha256(key: &str, value: &str) -> Result<String, ParseError> {
+    match value.strip_prefix("sha256:") {
+        Some(d) if d.len() == 64 && d.bytes().all(|b| b.is_ascii_hexdigit()) => {
+            Ok(d.to_lowercase())
+        }
+        _ => Err(ParseError::WrongFormat(
+            key.to_string(),
+            "sha256:<hex>".to_string(),
+        )),
+    }
+}
+
+/// Quality the `Accept` header gives `mime`, from the most specific matching range.
+///
+/// `*/*` is ignored, so clients that accept anything don't pick a negotiated target.
+fn accept_quality(accept: &str, mime: &Mime) -> f32 {
+    let mut best: Option<(u8, f32)> = None;
+    for range in accept.split(',') {
+        let mut params = range.split(';');
+        let Ok(range) = params.next().unwrap_or_default().trim().parse::<Mime>() else {
+            continue;
+        };
+        let specificity = if range.essence_str() == mime.essence_str() {
+            2
+        } else if range.type_() == mime.type_() && range.subtype() == mime::STAR {
+            1
+        } else {
+            continue;
+        };
+        let q = params
+            .filter_map(|d| d.trim().strip_prefix("q="))
+            .find_map(|d| d.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if best.is_none_or(|(s, _)| specificity > s) {
+            best = Some((specificity, q));
+        }
+    }
+    best.map_or(0.0, |(_, q)| q)
+}
+
+/// Parses `__HEADERS`, comma separated `Name:value` pairs.
+///
+/// Only a part starting with a header name directly followed by `:` begins a new header. Any
+/// other part continues the previous value, as in `no-cache, no-store` or `<a>, <b>`. `None`
+/// for invalid input, and for ambiguous input like `<a>, https://b`, where `https` would be
+/// taken as a header name.
+fn parse_headers(value: &str) -> Option<Vec<(HeaderName, String)>> {
+    let mut headers: Vec<(HeaderName, String)> = vec![];
+    for part in value.split(',') {
+        let new_header = part
+            .split_once(':')
+            .and_then(|(key, value)| Some((key.trim_start().parse::<HeaderName>().ok()?, value)));
+        match (new_header, headers.last_mut()) {
+            (Some((_, value)), _) if value.starts_with("//") => return None,
+            (Some((key, value)), _) => headers.push((key, value.trim().to_string())),
+            (None, _) if part.trim().is_empty() => {}
+            (None, Some((_, value))) => {
+                value.push(',');
+                value.push_str(part);
             }
-            Self::WrongFormat(key, expected_type) => {
-                error!("Variable \"{key}\" has wrong type, expected {expected_type}! Exiting.");
+            (None, None) => return None,
+        }
+    }
+    headers
+        .iter()
+        .all(|(_, d)| HeaderValue::from_str(d).is_ok())
+        .then_some(headers)
+}
+
+/// Fills in the placeholders of a handler's `__HEADERS`, leaving out headers whose value
+/// ends up invalid. Error responses get none of them.
+fn fill_headers<'a>(
+    rule: &str,
+    headers: &[(HeaderName, String)],
+    status: StatusCode,
+    lookup: impl Fn(&str) -> Option<&'a str>,
+) -> Vec<(HeaderName, HeaderValue)> {
+    if status.is_client_error() || status.is_server_error() {
+        return vec![];
+    }
+    let mut filled = vec![];
+    for (name, template) in headers.iter() {
+        let value = fill_placeholders(template, &lookup);
+        // Rejects CR, LF and other control characters coming from the request
+        match HeaderValue::from_str(&value) {
+            Ok(d) => filled.push((name.clone(), d)),
+            Err(_) => {
+                warn!("Not adding header {name} of handler {rule}, {value:?} isn't a valid value")
             }
         }
     }
+    filled
+}
+
+/// Replaces every `{NAME}` in `template` that `lookup` knows, leaving the others as they are.
+fn fill_placeholders<'a>(template: &str, lookup: impl Fn(&str) -> Option<&'a str>) -> String {
+    let mut filled = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        filled.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let value = rest
+            .find('}')
+            .and_then(|end| Some((lookup(&rest[1..end])?, end)));
+        match value {
+            Some((value, end)) => {
+                filled.push_str(value);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                filled.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    filled.push_str(rest);
+    filled
+}
+
+/// Settings that apply to the whole server rather than a single handler.
+#[derive(Debug, Clone)]
+struct GlobalConfig {
+    host: String,
+    startup_warn: Duration,
+    loop_protection: bool,
+    max_loop_depth: u32,
+    strict: bool,
+    normalize_targets: bool,
+    #[cfg(all(unix, feature = "unix-privdrop"))]
+    drop_privileges_to: Option<String>,
+    max_connections_per_ip: Option<u32>,
+    max_total_connections: Option<u32>,
+    slow_client_timeout: Option<Duration>,
+    max_queue_depth: Option<i64>,
+    backpressure_pause: Duration,
+    max_request_body: Option<u64>,
+    keepalive_timeout: Option<Duration>,
+    keepalive_max_requests: Option<u32>,
+    path_rewrite: PathRewrite,
+    log_sample_rate: f32,
+    proxy_connect_timeout: Duration,
+    proxy_read_timeout: Duration,
+}
+
+impl GlobalConfig {
+    fn from_env() -> Result<GlobalConfig, ParseError> {
+        let host = env::var(format!("{ENV_PREFIX}__HOST")).unwrap_or("0.0.0.0:8080".to_string());
+        let startup_warn =
+            Duration::from_millis(num_var(format!("{ENV_PREFIX}__STARTUP_WARN_MS"), 5000)?);
+        let loop_protection = bool_var(format!("{ENV_PREFIX}__LOOP_PROTECTION"))?;
+        let max_loop_depth = num_var(format!("{ENV_PREFIX}__MAX_LOOP_DEPTH"), 5)?;
+        let strict = bool_var(format!("{ENV_PREFIX}__STRICT"))?;
+        let normalize_targets = bool_var(format!("{ENV_PREFIX}__NORMALIZE_TARGETS"))?;
+        let template_hash = sha256_hex(REDIRECT_HTML_PAGE);
+        info!("HTML template hash: sha256:{template_hash}");
+        let template_hash_key = format!("{ENV_PREFIX}__HTML_TEMPLATE_HASH");
+        if let Ok(d) = env::var(&template_hash_key) {
+            if parse_sha256(&template_hash_key, &d)? != template_hash {
+                return Err(ParseError::HashMismatch(template_hash_key));
+            }
+        }
+        let path_rewrite_key = format!("{ENV_PREFIX}__PATH_REWRITE");
+        let pattern = match env::var(&path_rewrite_key) {
+            Ok(d) => match rewrite::parse_substitution(&d) {
+                Some(d) => Some(d),
+                None => {
+                    return Err(ParseError::WrongFormat(
+                        path_rewrite_key,
+                        "s|regex|replacement|".to_string(),
+                    ))
+                }
+            },
+            Err(_) => None,
+        };
+        let path_rewrite = PathRewrite {
+            pattern,
+            strip_prefix: env::var(format!("{ENV_PREFIX}__STRIP_PREFIX"))
+                .ok()
+                .and_then(|d| rewrite::parse_prefix(&d)),
+            add_prefix: env::var(format!("{ENV_PREFIX}__ADD_PREFIX"))
+                .ok()
+                .and_then(|d| rewrite::parse_prefix(&d)),
+        };
+        let drop_privileges_to = env::var(format!("{ENV_PREFIX}__DROP_PRIVILEGES_TO")).ok();
+        // Serving on as root isn't an acceptable fallback for a drop that was asked for
+        if drop_privileges_to.is_some() && !cfg!(all(unix, feature = "unix-privdrop")) {
+            return Err(ParseError::PrivilegeDrop(if cfg!(unix) {
+                "built without the unix-privdrop feature".to_string()
+            } else {
+                "not supported on this platform".to_string()
+            }));
+        }
+        Ok(GlobalConfig {
+            host,
+            startup_warn,
+            loop_protection,
+            max_loop_depth,
+            strict,
+            normalize_targets,
+            #[cfg(all(unix, feature = "unix-privdrop"))]
+            drop_privileges_to,
+            max_connections_per_ip: opt_num_var(format!("{ENV_PREFIX}__MAX_CONNECTIONS_PER_IP"))?,
+            max_total_connections: opt_num_var(format!("{ENV_PREFIX}__MAX_TOTAL_CONNECTIONS"))?,
+            slow_client_timeout: opt_num_var(format!("{ENV_PREFIX}__SLOW_CLIENT_TIMEOUT_MS"))?
+                .map(Duration::from_millis),
+            max_queue_depth: opt_num_var(format!("{ENV_PREFIX}__MAX_QUEUE_DEPTH"))?,
+            backpressure_pause: Duration::from_millis(num_var(
+                format!("{ENV_PREFIX}__BACKPRESSURE_PAUSE_MS"),
+                100,
+            )?),
+            max_request_body: byte_var(format!("{ENV_PREFIX}__MAX_REQUEST_BODY"))?,
+            // 0 turns either limit off
+            keepalive_timeout: Some(num_var(
+                format!("{ENV_PREFIX}__KEEPALIVE_TIMEOUT_SECS"),
+                75,
+            )?)
+            .filter(|d| *d > 0)
+            .map(Duration::from_secs),
+            keepalive_max_requests: Some(num_var(
+                format!("{ENV_PREFIX}__KEEPALIVE_MAX_REQUESTS"),
+                1000,
+            )?)
+            .filter(|d| *d > 0),
+            path_rewrite,
+            log_sample_rate: rate_var(format!("{ENV_PREFIX}__LOG_SAMPLE_RATE"))?.unwrap_or(1.0),
+            proxy_connect_timeout: Duration::from_millis(num_var(
+                format!("{ENV_PREFIX}__PROXY_CONNECT_TIMEOUT_MS"),
+                5000,
+            )?),
+            proxy_read_timeout: Duration::from_millis(num_var(
+                format!("{ENV_PREFIX}__PROXY_READ_TIMEOUT_MS"),
+                30000,
+            )?),
+        })
+    }
+}
+
+/// Sends the client to `target` instead when the request header `header` equals `value`.
+#[derive(Debug, Clone)]
+struct HeaderCondition {
+    header: String,
+    value: String,
+    target: String,
 }
 
 #[derive(Debug, Clone)]
 struct RedirEntry {
+    name: String,
     paths: Vec<String>,
     target: String,
     code: StatusCode,
     js_only: bool,
+    strip_query: bool,
     preserve_params: bool,
+    encode_target: bool,
+    file: Option<PathBuf>,
+    file_content_type: Option<Mime>,
+    file_range: bool,
+    proxy: Option<ProxyOptions>,
+    conditions: Vec<HeaderCondition>,
+    max_request_body: Option<u64>,
+    /// Targets for clients preferring the given content type, in the `Accept` header.
+    accept_targets: Vec<(Mime, String)>,
+    /// Query parameters that must all be present for the handler to match.
+    require_params: Vec<String>,
+    /// Level of the access log line, `None` to not log the handler's requests at all.
+    log_level: Option<Level>,
+    log_sample_rate: f32,
+    /// Handler whose fields this one inherited.
+    inherits: Option<String>,
+    /// Extra response headers, their values may contain placeholders.
+    headers: Vec<(HeaderName, String)>,
+    /// Time over which the handler's share of its requests ramps up from 1% to all of them.
+    slow_start: Option<Duration>,
 }
 
 impl RedirEntry {
-    fn from_vars(name: &str) -> Result<RedirEntry, ParseError> {
+    fn from_vars(config: &GlobalConfig, name: &str) -> Result<RedirEntry, ParseError> {
         let paths_key = format!("{ENV_PREFIX}_{name}");
         let paths: Vec<String> = match env::var(&paths_key) {
             Ok(d) => d
@@ -47,11 +466,96 @@ impl RedirEntry {
                 .collect(),
             Err(_) => return Err(ParseError::Missing(paths_key)),
         };
+        // Rules serving a file don't redirect, so they need neither target nor code.
+        // Proxies need a target only.
+        let file = env::var(format!("{ENV_PREFIX}_{name}__FILE"))
+            .ok()
+            .map(PathBuf::from);
+        if let Some(path) = &file {
+            if !path.is_file() {
+                warn!(
+                    "File {} of handler {name} doesn't exist (yet)",
+                    path.display()
+                );
+            }
+        }
+        let file_content_type_key = format!("{ENV_PREFIX}_{name}__FILE_CONTENT_TYPE");
+        let file_content_type = match env::var(&file_content_type_key) {
+            Ok(d) => match d.parse::<Mime>() {
+                Ok(d) => Some(d),
+                Err(_) => {
+                    return Err(ParseError::WrongFormat(
+                        file_content_type_key,
+                        "MIME type".to_string(),
+                    ))
+                }
+            },
+            Err(_) => None,
+        };
+        let file_range = bool_var(format!("{ENV_PREFIX}_{name}__FILE_RANGE"))?;
         let target_key = format!("{ENV_PREFIX}_{name}__TARGET");
         let target = match env::var(&target_key) {
             Ok(d) => d,
+            Err(_) if file.is_some() => String::new(),
             Err(_) => return Err(ParseError::Missing(target_key)),
         };
+        let target_hash_key = format!("{ENV_PREFIX}_{name}__TARGET_HASH");
+        if let Ok(d) = env::var(&target_hash_key) {
+            let expected = parse_sha256(&target_hash_key, &d)?;
+            let actual = sha256_hex(&target);
+            if actual != expected {
+                let e = ParseError::HashMismatch(target_hash_key);
+                if config.strict {
+                    return Err(e);
+                }
+                error!("{e}, target is sha256:{actual}");
+            }
+        }
+        let normalize =
+            bool_var(format!("{ENV_PREFIX}_{name}__NORMALIZE_TARGET"))? || config.normalize_targets;
+        // Every target a handler can pick goes through this, not just the main one
+        let normalize_target = |key: String, target: String| -> Result<String, ParseError> {
+            if target.is_empty() || !normalize {
+                return Ok(target);
+            }
+            let normalized = match Url::parse(&target) {
+                Ok(d) => d.to_string(),
+                Err(_) => return Err(ParseError::WrongFormat(key, "URL".to_string())),
+            };
+            if normalized != target {
+                info!("Normalized target of {name} from {target} to {normalized}");
+            }
+            Ok(normalized)
+        };
+        let target = normalize_target(target_key, target)?;
+        let max_request_body =
+            byte_var(format!("{ENV_PREFIX}_{name}__MAX_REQUEST_BODY"))?.or(config.max_request_body);
+        let proxy = if bool_var(format!("{ENV_PREFIX}_{name}__PROXY"))? {
+            let forward_headers = env::var(format!("{ENV_PREFIX}_{name}__FORWARD_HEADERS"))
+                .ok()
+                .map(|d| {
+                    d.split(',')
+                        .map(str::trim)
+                        .filter(|s| !s.is_empty())
+                        .map(str::to_lowercase)
+                        .collect()
+                });
+            let max_response_body = byte_var(format!("{ENV_PREFIX}_{name}__MAX_RESPONSE_BODY"))?;
+            let coalesce = bool_var(format!("{ENV_PREFIX}_{name}__COALESCE"))?;
+            let coalesce_timeout = Duration::from_millis(num_var(
+                format!("{ENV_PREFIX}_{name}__COALESCE_TIMEOUT_MS"),
+                30000,
+            )?);
+            Some(ProxyOptions {
+                forward_headers,
+                max_response_body,
+                coalesce,
+                coalesce_timeout,
+                max_request_body,
+            })
+        } else {
+            None
+        };
         let code_key = format!("{ENV_PREFIX}_{name}__CODE");
         let code = match env::var(&code_key) {
             Ok(d) => match d.parse::<u16>() {
@@ -61,46 +565,181 @@ impl RedirEntry {
                 },
                 Err(_) => return Err(ParseError::WrongFormat(code_key, "Integer".to_string())),
             },
+            Err(_) if file.is_some() || proxy.is_some() => StatusCode::OK,
             Err(_) => return Err(ParseError::Missing(code_key)),
         };
-        let js_only_key = format!("{ENV_PREFIX}_{name}__JS_ONLY");
-        let js_only = match env::var(&js_only_key) {
-            Ok(d) => match d.to_lowercase().parse::<bool>() {
+        let mut conditions: Vec<HeaderCondition> = vec![];
+        for i in 1..=MAX_CONDITIONS {
+            // The first condition has no suffix, the following ones are numbered from 2
+            let suffix = if i == 1 {
+                String::new()
+            } else {
+                format!("_{i}")
+            };
+            let Ok(header) = env::var(format!("{ENV_PREFIX}_{name}__CONDITION_HEADER{suffix}"))
+            else {
+                continue;
+            };
+            let value_key = format!("{ENV_PREFIX}_{name}__CONDITION_HEADER_VALUE{suffix}");
+            let value = match env::var(&value_key) {
                 Ok(d) => d,
-                Err(_) => return Err(ParseError::WrongFormat(js_only_key, "Boolean".to_string())),
-            },
-            Err(_) => false,
+                Err(_) => return Err(ParseError::Missing(value_key)),
+            };
+            let target_key = format!("{ENV_PREFIX}_{name}__CONDITION_TARGET{suffix}");
+            let target = match env::var(&target_key) {
+                Ok(d) => normalize_target(target_key, d)?,
+                Err(_) => return Err(ParseError::Missing(target_key)),
+            };
+            conditions.push(HeaderCondition {
+                header,
+                value,
+                target,
+            });
+        }
+        let mut accept_targets: Vec<(Mime, String)> = vec![];
+        for (kind, mime) in [("JSON", mime::APPLICATION_JSON), ("HTML", mime::TEXT_HTML)] {
+            let key = format!("{ENV_PREFIX}_{name}__ACCEPT_{kind}_TARGET");
+            if let Ok(d) = env::var(&key) {
+                accept_targets.push((mime, normalize_target(key, d)?));
+            }
+        }
+        let headers_key = format!("{ENV_PREFIX}_{name}__HEADERS");
+        let headers = match parse_headers(&env::var(&headers_key).unwrap_or_default()) {
+            Some(d) => d,
+            None => {
+                return Err(ParseError::WrongFormat(
+                    headers_key,
+                    "comma separated Name:value pairs".to_string(),
+                ))
+            }
         };
-        let preserve_params_key = format!("{ENV_PREFIX}_{name}__PRESERVE_PARAMS");
-        let preserve_params = match env::var(&preserve_params_key) {
-            Ok(d) => match d.to_lowercase().parse::<bool>() {
-                Ok(d) => d,
+        let js_only = bool_var(format!("{ENV_PREFIX}_{name}__JS_ONLY"))?;
+        let strip_query = bool_var(format!("{ENV_PREFIX}_{name}__STRIP_QUERY"))?;
+        let preserve_params = bool_var(format!("{ENV_PREFIX}_{name}__PRESERVE_PARAMS"))?;
+        let encode_target = bool_var(format!("{ENV_PREFIX}_{name}__ENCODE_TARGET"))?;
+        let require_params = env::var(format!("{ENV_PREFIX}_{name}__REQUIRE_PARAMS"))
+            .map(|d| {
+                d.split(',')
+                    .map(str::trim)
+                    .filter(|s| !s.is_empty())
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default();
+        let log_level_key = format!("{ENV_PREFIX}_{name}__LOG_LEVEL");
+        let log_level = match env::var(&log_level_key) {
+            Ok(d) if d.eq_ignore_ascii_case("off") => None,
+            Ok(d) => match d.parse::<Level>() {
+                Ok(d) => Some(d),
                 Err(_) => {
                     return Err(ParseError::WrongFormat(
-                        preserve_params_key,
-                        "Boolean".to_string(),
+                        log_level_key,
+                        "trace, debug, info, warn, error or off".to_string(),
                     ))
                 }
             },
-            Err(_) => false,
+            Err(_) => Some(Level::INFO),
         };
         Ok(RedirEntry {
+            name: name.to_string(),
             paths,
             target,
             code,
             js_only,
+            strip_query,
             preserve_params,
+            encode_target,
+            file,
+            file_content_type,
+            file_range,
+            proxy,
+            conditions,
+            accept_targets,
+            max_request_body,
+            require_params,
+            log_level,
+            log_sample_rate: rate_var(format!("{ENV_PREFIX}_{name}__LOG_SAMPLE_RATE"))?
+                .unwrap_or(config.log_sample_rate),
+            inherits: env::var(format!("{ENV_PREFIX}_{name}__INHERITS")).ok(),
+            headers,
+            slow_start: Some(num_var(format!("{ENV_PREFIX}_{name}__SLOW_START_SECS"), 0)?)
+                .filter(|d| *d > 0)
+                .map(Duration::from_secs),
         })
     }
 
+    /// Human readable summary of what the handler does, used by `--print-routes`.
+    fn describe(&self) -> String {
+        if let Some(path) = &self.file {
+            format!("file {}", path.display())
+        } else if self.proxy.is_some() {
+            format!("proxy {}", self.target)
+        } else if self.js_only {
+            format!("{} (JS)", self.target)
+        } else {
+            format!("{} ({})", self.target, self.code.as_u16())
+        }
+    }
+
+    /// Builds the URL to send the client to, or `None` (after logging) if it's unusable.
+    fn build_target(&self, req: &Request) -> Option<String> {
+        // Proxies are transparent, they always pass the query on
+        let params: String = if self.preserve_params || self.proxy.is_some() {
+            req.uri().query().unwrap_or_default().to_string()
+        } else {
+            "".to_string()
+        };
+        let base = self
+            .conditions
+            .iter()
+            .find(|c| {
+                req.headers()
+                    .get(&c.header)
+                    .is_some_and(|d| d == c.value.as_str())
+            })
+            .map(|c| &c.target)
+            .or_else(|| self.negotiate(req))
+            .unwrap_or(&self.target);
+        let target = if params.is_empty() {
+            base.to_string()
+        } else {
+            format!("{}?{}", base, params)
+        };
+        if self.encode_target {
+            return match Url::parse(&target) {
+                Ok(d) => Some(d.to_string()),
+                Err(e) => {
+                    error!("Target \"{target}\" can't be parsed as URL: {e}");
+                    None
+                }
+            };
+        }
+        Some(target)
+    }
+
+    /// Picks the accept target the client prefers most, if it accepts any of them.
+    fn negotiate(&self, req: &Request) -> Option<&String> {
+        let accept = req.headers().get(ACCEPT)?.to_str().ok()?;
+        let mut best: Option<(f32, &String)> = None;
+        for (mime, target) in self.accept_targets.iter() {
+            let q = accept_quality(accept, mime);
+            if q > 0.0 && best.is_none_or(|(b, _)| q > b) {
+                best = Some((q, target));
+            }
+        }
+        best.map(|(_, d)| d)
+    }
+
     fn extract_names() -> Vec<String> {
-        let re = Regex::new(&format!(r"^{ENV_PREFIX}_([a-zA-Z0-9]+)$")).unwrap();
+        let prefix = format!("{ENV_PREFIX}_");
         let mut names: Vec<String> = vec![];
         for (key, _) in env::vars() {
-            if re.find_iter(&key).next().is_some() {
-                // This will return the captured groups
-                let caps = re.captures(&key).unwrap(); // If we have a match, use captures to get the group
-                names.push(caps[1].to_string()); // As_str converts &str to String
+            // Cheap prefix check first, most env vars aren't ours
+            if !key.starts_with(&prefix) {
+                continue;
+            }
+            if let Some(caps) = NAME_RE.captures(&key) {
+                names.push(caps[1].to_string());
             }
         }
         names
@@ -116,26 +755,129 @@ impl RedirEntry {
         Ok(map)
     }
     */
-    fn get_routers() -> Result<Vec<Router>, ParseError> {
+    fn get_entries(config: &GlobalConfig) -> Result<Vec<RedirEntry>, ParseError> {
         let names: Vec<String> = RedirEntry::extract_names();
         info!("Names found: {:?}", &names);
-        let mut routers: Vec<Router> = vec![];
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for name in names.iter() {
+            *counts.entry(name).or_default() += 1;
+        }
+        if let Some((name, _)) = counts.iter().find(|(_, count)| **count > 1) {
+            return Err(ParseError::DuplicateName(name.to_string()));
+        }
+        let mut entries: Vec<RedirEntry> = vec![];
         for name in names {
+            if bool_var(format!("{ENV_PREFIX}_{name}__ABSTRACT"))? {
+                info!("Handler {name} is abstract, only inherited from");
+                continue;
+            }
             info!("Found handler: {}", &name);
-            let entry = RedirEntry::from_vars(&name)?;
-            for path in entry.clone().paths {
-                info!("Handler registered for {}", &path);
-                routers.push(Router::with_path(path).get(RedirEntryHandler {
+            entries.push(RedirEntry::from_vars(config, &name)?);
+        }
+        Ok(entries)
+    }
+
+    /// The entries in the order their routes are tried.
+    ///
+    /// Handlers with required params or a slow start come first, so a handler without either
+    /// can share the path and take every request they don't match.
+    fn routing_order(entries: &[RedirEntry]) -> Vec<&RedirEntry> {
+        let (conditional, unconditional): (Vec<_>, Vec<_>) = entries
+            .iter()
+            .partition(|d| !d.require_params.is_empty() || d.slow_start.is_some());
+        conditional.into_iter().chain(unconditional).collect()
+    }
+
+    fn get_routers(config: &Arc<GlobalConfig>, entries: &[RedirEntry]) -> Vec<Router> {
+        let mut routers: Vec<Router> = vec![];
+        for entry in RedirEntry::routing_order(entries) {
+            let slow_start = entry
+                .slow_start
+                .map(|d| Arc::new(SlowStart::new(&entry.name, d)));
+            for path in entry.paths.iter() {
+                info!("Handler {} registered for {}", &entry.name, &path);
+                let handler = RedirEntryHandler {
                     entry: entry.clone().into(),
-                }));
+                    config: config.clone(),
+                };
+                // Proxies pass every method through, redirects only answer GET
+                let mut router = Router::with_path(path);
+                if let Some(limit) = entry.max_request_body {
+                    router = router.hoop(RequestBodyLimit(limit));
+                }
+                if !entry.require_params.is_empty() {
+                    let required = entry.require_params.clone();
+                    router = router.filter_fn(move |req, _| {
+                        required.iter().all(|d| req.queries().contains_key(d))
+                    });
+                }
+                if let Some(slow_start) = slow_start.clone() {
+                    router = router.filter_fn(move |_, _| slow_start.admit());
+                }
+                routers.push(if entry.proxy.is_some() {
+                    router.goal(handler)
+                } else {
+                    router.get(handler)
+                });
             }
         }
-        Ok(routers)
+        routers
+    }
+}
+
+/// Lets a growing share of requests through to a handler, from 1% at startup to all of them
+/// after `duration`. The others fall through to the next handler for the path, or the 404.
+struct SlowStart {
+    name: String,
+    since: Instant,
+    duration: Duration,
+    last_log: Mutex<Instant>,
+}
+
+impl SlowStart {
+    fn new(name: &str, duration: Duration) -> Self {
+        info!(
+            "Handler {name} starts slowly, ramping up over {}s",
+            duration.as_secs()
+        );
+        let now = Instant::now();
+        SlowStart {
+            name: name.to_string(),
+            since: now,
+            duration,
+            last_log: Mutex::new(now),
+        }
+    }
+
+    /// Share of requests let through `elapsed` into the ramp, growing linearly from 1% to all.
+    fn share(elapsed: Duration, duration: Duration) -> f32 {
+        if elapsed >= duration {
+            return 1.0;
+        }
+        (elapsed.as_secs_f32() / duration.as_secs_f32()).max(0.01)
+    }
+
+    fn admit(&self) -> bool {
+        let share = SlowStart::share(self.since.elapsed(), self.duration);
+        if share >= 1.0 {
+            return true;
+        }
+        let mut last_log = self.last_log.lock().unwrap();
+        if last_log.elapsed() >= Duration::from_secs(60) {
+            info!(
+                "Handler {} gets {:.0}% of its traffic",
+                self.name,
+                share * 100.0
+            );
+            *last_log = Instant::now();
+        }
+        fastrand::f32() < share
     }
 }
 
 pub struct RedirEntryHandler {
     entry: RwLock<RedirEntry>,
+    config: Arc<GlobalConfig>,
 }
 
 #[async_trait]
@@ -143,62 +885,606 @@ impl Handler for RedirEntryHandler {
     async fn handle(
         &self,
         req: &mut Request,
-        _depot: &mut Depot,
+        depot: &mut Depot,
         res: &mut Response,
         _ctrl: &mut FlowCtrl,
     ) {
-        let entry = self.entry.read().unwrap();
-        let params: String = if entry.preserve_params {
-            req.uri().query().unwrap_or_default().to_string()
-        } else {
-            "".to_string()
+        {
+            let entry = self.entry.read().unwrap();
+            depot.inject(AccessLog {
+                level: entry.log_level,
+                sample_rate: entry.log_sample_rate,
+            });
+        }
+        let target = self.respond(req, res).await;
+        self.add_headers(req, res, target.as_deref());
+    }
+}
+
+impl RedirEntryHandler {
+    /// Answers the request, returning the target it was sent to, if it got that far.
+    async fn respond(&self, req: &mut Request, res: &mut Response) -> Option<String> {
+        // The guard isn't Send, so it must not be held across any I/O
+        let file = {
+            let entry = self.entry.read().unwrap();
+            entry
+                .file
+                .clone()
+                .map(|path| (path, entry.file_content_type.clone(), entry.file_range))
         };
-        let target = if params.is_empty() {
-            entry.target.to_string()
-        } else {
-            format!("{}?{}", entry.target, params)
+        if let Some((path, content_type, range)) = file {
+            send_file(path, content_type, range, req, res).await;
+            return None;
+        }
+        let proxy = {
+            let entry = self.entry.read().unwrap();
+            entry
+                .proxy
+                .clone()
+                .map(|options| (entry.name.clone(), entry.build_target(req), options))
         };
+        if let Some((name, target, options)) = proxy {
+            match &target {
+                Some(target) => proxy::forward(&name, target, &options, req, res).await,
+                None => {
+                    res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
+                }
+            }
+            return target;
+        }
+        let entry = self.entry.read().unwrap();
         let mut headers = HeaderMap::new();
+        if self.config.loop_protection {
+            // A missing or garbled counter means this is the first hop
+            let count = req.header::<u32>(REDIRECT_COUNT_HEADER).unwrap_or_default();
+            if count >= self.config.max_loop_depth {
+                error!(
+                    "Redirect loop detected for handler {} after {count} hops",
+                    entry.name
+                );
+                res.status_code(StatusCode::LOOP_DETECTED);
+                return None;
+            }
+            headers.append(REDIRECT_COUNT_HEADER, (count + 1).into());
+        }
+        let Some(target) = entry.build_target(req) else {
+            res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
+            return None;
+        };
+        if !entry.accept_targets.is_empty() {
+            headers.append(VARY, HeaderValue::from_static("Accept"));
+        }
+        // The target depends on these, so caches must not share it across their values
+        let mut vary: Vec<&str> = vec![];
+        for condition in entry.conditions.iter() {
+            if !vary
+                .iter()
+                .any(|d| d.eq_ignore_ascii_case(&condition.header))
+            {
+                vary.push(&condition.header);
+            }
+        }
+        for name in vary {
+            if let Ok(d) = HeaderValue::from_str(name) {
+                headers.append(VARY, d);
+            }
+        }
         if entry.js_only {
-            let page = REDIRECT_HTML_PAGE.replace("{REDIRECT_URL}", &target);
-            headers.append("Content-Type", "text/html".parse().unwrap());
+            let mut page = REDIRECT_HTML_PAGE.replace("{REDIRECT_URL}", &target);
+            if entry.strip_query {
+                page = page.replacen("<head>", &format!("<head>{STRIP_QUERY_SCRIPT}"), 1);
+            }
+            headers.append(
+                "Content-Type",
+                HeaderValue::from_static("text/html; charset=utf-8"),
+            );
             res.status_code(StatusCode::OK);
             res.set_headers(headers);
-            res.render(Text::Html(page));
-            return;
+            // Sent as a stream with the <head> as its own chunk, so larger templates can get
+            // their meta tags out before the rest of the page is ready
+            res.stream(stream::iter(
+                split_head(page).map(|d| Ok::<_, io::Error>(Bytes::from(d))),
+            ));
         } else {
-            headers.append(
-                "Location",
-                target.parse().unwrap(),
-            );
+            match target.parse() {
+                Ok(d) => {
+                    headers.append("Location", d);
+                }
+                Err(_) => {
+                    error!("Target \"{target}\" is not a valid Location header value");
+                    res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
+                    return None;
+                }
+            }
             res.set_headers(headers);
             res.status_code(entry.code);
+        }
+        Some(target)
+    }
+
+    /// Adds the handler's `__HEADERS`, with their placeholders filled in, to the response.
+    /// `target` is `None` when no target was built, `{TARGET}` is left as it is then.
+    fn add_headers(&self, req: &Request, res: &mut Response, target: Option<&str>) {
+        let entry = self.entry.read().unwrap();
+        if entry.headers.is_empty() {
             return;
         }
+        let status = res.status_code.unwrap_or(StatusCode::OK);
+        let code = status.as_u16().to_string();
+        let headers = fill_headers(&entry.name, &entry.headers, status, |d| match d {
+            "RULE_NAME" => Some(entry.name.as_str()),
+            "TARGET" => target,
+            "CODE" => Some(code.as_str()),
+            "PATH" => Some(req.uri().path()),
+            "QUERY" => Some(req.uri().query().unwrap_or_default()),
+            _ => None,
+        });
+        for (name, value) in headers {
+            res.headers_mut().append(name, value);
+        }
     }
 }
+
+/// Streams a file as the response, honouring conditional and (optionally) range requests.
+async fn send_file(
+    path: PathBuf,
+    content_type: Option<Mime>,
+    range: bool,
+    req: &Request,
+    res: &mut Response,
+) {
+    let mut builder = NamedFile::builder(path);
+    if let Some(content_type) = content_type {
+        builder = builder.content_type(content_type);
+    }
+    if range {
+        builder.send(req.headers(), res).await;
+    } else {
+        let mut req_headers = req.headers().clone();
+        req_headers.remove(RANGE);
+        req_headers.remove(IF_RANGE);
+        builder.send(&req_headers, res).await;
+        res.headers_mut()
+            .insert(ACCEPT_RANGES, HeaderValue::from_static("none"));
+    }
+}
+
 #[handler]
 async fn error_handler(res: &mut Response) {
     res.status_code(StatusCode::NOT_FOUND);
     res.render("");
 }
 
-#[tokio::main]
-async fn main() {
-    tracing_subscriber::fmt().init();
-    let routers = match RedirEntry::get_routers() {
+fn build_runtime() -> Result<Runtime, ParseError> {
+    let runtime_key = format!("{ENV_PREFIX}__RUNTIME");
+    let mut single_thread = false;
+    let mut builder = match env::var(&runtime_key) {
+        Ok(d) => match d.to_lowercase().as_str() {
+            "single_thread" => {
+                single_thread = true;
+                runtime::Builder::new_current_thread()
+            }
+            "multi_thread" => runtime::Builder::new_multi_thread(),
+            _ => {
+                return Err(ParseError::WrongFormat(
+                    runtime_key,
+                    "\"single_thread\" or \"multi_thread\"".to_string(),
+                ))
+            }
+        },
+        Err(_) => runtime::Builder::new_multi_thread(),
+    };
+    let worker_threads_key = format!("{ENV_PREFIX}__WORKER_THREADS");
+    if let Ok(d) = env::var(&worker_threads_key) {
+        match d.parse::<usize>() {
+            Ok(_) if single_thread => {
+                warn!("Ignoring {worker_threads_key}, the single_thread runtime has no workers");
+            }
+            Ok(d) if d > 0 => {
+                builder.worker_threads(d);
+            }
+            _ => {
+                return Err(ParseError::WrongFormat(
+                    worker_threads_key,
+                    "positive Integer".to_string(),
+                ))
+            }
+        }
+    }
+    Ok(builder
+        .enable_all()
+        .build()
+        .expect("Failed to build tokio runtime"))
+}
+
+fn main() {
+    let routes_only = env::args().skip(1).any(|d| d == "--print-routes");
+    if routes_only {
+        // Keep stdout for the routes themselves
+        tracing_subscriber::fmt().with_writer(io::stderr).init();
+    } else {
+        tracing_subscriber::fmt().init();
+    }
+    let phase = Instant::now();
+    let env_file_load = match envfile::load().and_then(|loaded| {
+        let elapsed = phase.elapsed();
+        inherit::apply()?;
+        Ok(loaded.then_some(elapsed))
+    }) {
         Ok(d) => d,
         Err(e) => {
             e.unpack();
-            return;
+            process::exit(1);
         }
     };
+    if routes_only {
+        if let Err(e) = print_routes() {
+            e.unpack();
+            process::exit(1);
+        }
+        return;
+    }
+    if let Err(e) = build_runtime().and_then(|runtime| runtime.block_on(serve(env_file_load))) {
+        e.unpack();
+        process::exit(1);
+    }
+}
+
+fn build_router(config: &Arc<GlobalConfig>, entries: &[RedirEntry]) -> Router {
     let mut router = Router::new();
-    for redir_router in routers.into_iter() {
+    if !config.path_rewrite.is_noop() {
+        router = router.filter(config.path_rewrite.clone());
+    }
+    for redir_router in RedirEntry::get_routers(config, entries).into_iter() {
         router = router.push(redir_router);
     }
-    router = router.push(Router::new().goal(error_handler));
-    let interface = env::var(format!("{ENV_PREFIX}__HOST")).unwrap_or("0.0.0.0:8080".to_string());
-    let acceptor = TcpListener::new(interface).bind().await;
-    Server::new(acceptor).serve(router).await;
+    router.push(Router::new().goal(error_handler))
+}
+
+/// `depth` is shared with the acceptor, which pauses while it is too high, and `guard` with
+/// the server, which asks it about every new connection.
+fn build_service(
+    config: &GlobalConfig,
+    router: Router,
+    depth: &QueueDepth,
+    guard: &ConnectionGuard,
+) -> Service {
+    // Service level hoops also run for requests that match no route.
+    // Keep the list in print_routes() in sync.
+    let mut service = Service::new(router).hoop(RequestTraceMiddleware {
+        sample_rate: config.log_sample_rate,
+    });
+    if config.max_queue_depth.is_some() {
+        service = service.hoop(depth.clone());
+    }
+    service.hoop(guard.clone())
+}
+
+/// Prints what every registered path does, followed by salvo's own view of the router.
+fn print_routes() -> Result<(), ParseError> {
+    let config = Arc::new(GlobalConfig::from_env()?);
+    let entries = RedirEntry::get_entries(&config)?;
+    for entry in RedirEntry::routing_order(&entries) {
+        let method = if entry.proxy.is_some() { "*" } else { "GET" };
+        for path in entry.paths.iter() {
+            let params = if entry.require_params.is_empty() {
+                String::new()
+            } else {
+                format!("?{}", entry.require_params.join("&"))
+            };
+            let inherits = entry
+                .inherits
+                .as_ref()
+                .map_or(String::new(), |d| format!(", inherits {d}"));
+            // The route's own hoops and filters, as get_routers() adds them
+            let mut middleware = vec![];
+            if let Some(limit) = entry.max_request_body {
+                middleware.push(format!("body limit {limit} bytes"));
+            }
+            if !entry.require_params.is_empty() {
+                middleware.push(format!(
+                    "required params {}",
+                    entry.require_params.join(", ")
+                ));
+            }
+            if let Some(d) = entry.slow_start {
+                middleware.push(format!("slow start over {}s", d.as_secs()));
+            }
+            let middleware = if middleware.is_empty() {
+                String::new()
+            } else {
+                format!(" ({})", middleware.join("; "))
+            };
+            println!(
+                "{method} {path}{params} → {} [{}{inherits}]{middleware}",
+                entry.describe(),
+                entry.name
+            );
+        }
+    }
+    println!("* <anything else> → 404");
+    if !config.path_rewrite.is_noop() {
+        println!(
+            "Paths are rewritten before matching: {}",
+            config.path_rewrite.describe()
+        );
+    }
+    let mut middleware = vec!["request tracing"];
+    if config.max_queue_depth.is_some() {
+        middleware.push("queue depth tracking");
+    }
+    middleware.push("connection limits and keep-alive");
+    println!("Middleware on every request: {}", middleware.join(", "));
+    println!();
+    println!("{:?}", build_router(&config, &entries));
+    Ok(())
+}
+
+/// Splits a page after its `</head>`, the whole page going into the second part without one.
+fn split_head(mut page: String) -> [String; 2] {
+    let split = page.find("</head>").map_or(0, |d| d + "</head>".len());
+    let body = page.split_off(split);
+    [page, body]
+}
+
+/// Logs how long a startup phase took, escalating to a warning when it was slow.
+fn log_phase(config: &GlobalConfig, phase: &str, elapsed: Duration, detail: &str) {
+    let ms = elapsed.as_millis();
+    if elapsed > config.startup_warn {
+        warn!("[startup] {phase}: {ms}ms{detail}");
+    } else {
+        info!("[startup] {phase}: {ms}ms{detail}");
+    }
+}
+
+/// `env_file_load` is how long loading the env file took, if there was one.
+async fn serve(env_file_load: Option<Duration>) -> Result<(), ParseError> {
+    let startup = Instant::now();
+    let phase = Instant::now();
+    let config = Arc::new(GlobalConfig::from_env()?);
+    let entries = RedirEntry::get_entries(&config)?;
+    proxy::init_client(config.proxy_connect_timeout, config.proxy_read_timeout);
+    // Logged only now, the threshold is part of the config
+    if let Some(elapsed) = env_file_load {
+        log_phase(&config, "config file loading", elapsed, "");
+    }
+    log_phase(&config, "env var parsing", phase.elapsed(), "");
+
+    let phase = Instant::now();
+    let router = build_router(&config, &entries);
+    log_phase(
+        &config,
+        "router construction",
+        phase.elapsed(),
+        &format!(" ({} rules)", entries.len()),
+    );
+
+    let acceptor = TcpListener::new(config.host.clone()).bind().await;
+    // Privileged ports are bound by now, nothing after this needs root
+    #[cfg(all(unix, feature = "unix-privdrop"))]
+    if let Some(spec) = &config.drop_privileges_to {
+        privdrop::drop_to(spec).map_err(ParseError::PrivilegeDrop)?;
+    }
+    log_phase(&config, "total", startup.elapsed(), "");
+    let depth = QueueDepth::default();
+    let acceptor = BackpressureAcceptor::new(
+        acceptor,
+        depth.clone(),
+        config.max_queue_depth,
+        config.backpressure_pause,
+    );
+    if config.log_sample_rate < 1.0 {
+        info!(
+            "Access logging at {:.1}% sample rate (errors always logged)",
+            config.log_sample_rate * 100.0
+        );
+    }
+    info!(
+        "Keep-alive: idle timeout {}, at most {} requests per connection",
+        config
+            .keepalive_timeout
+            .map_or("off".to_string(), |d| format!("{}s", d.as_secs())),
+        config
+            .keepalive_max_requests
+            .map_or("unlimited".to_string(), |d| d.to_string()),
+    );
+    let guard = ConnectionGuard::new(ConnectionLimits {
+        max_per_ip: config.max_connections_per_ip,
+        max_total: config.max_total_connections,
+        slow_client_timeout: config.slow_client_timeout,
+        keepalive_timeout: config.keepalive_timeout,
+        keepalive_max_requests: config.keepalive_max_requests,
+    });
+    let service = build_service(&config, router, &depth, &guard);
+    let server = Server::new(acceptor).fuse_factory(guard);
+    server.serve(service).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::BodyExt;
+
+    use super::*;
+
+    #[test]
+    fn split_head_after_closing_tag() {
+        let page = REDIRECT_HTML_PAGE.replace("{REDIRECT_URL}", "https://example.com");
+        let [head, body] = split_head(page.clone());
+        assert!(head.ends_with("</head>"));
+        assert!(body.starts_with("<body>"));
+        assert_eq!(head + &body, page);
+        assert_eq!(
+            split_head("<p>no head</p>".to_string()),
+            [String::new(), "<p>no head</p>".to_string()]
+        );
+    }
+
+    #[tokio::test]
+    async fn streamed_chunks_arrive_intact() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<Result<Bytes, io::Error>>(1);
+        let mut res = Response::new();
+        res.stream(stream::poll_fn(move |cx| rx.poll_recv(cx)));
+        let page = REDIRECT_HTML_PAGE.replace("{REDIRECT_URL}", "https://example.com");
+        let chunks: Vec<Bytes> = page
+            .as_bytes()
+            .chunks(16)
+            .map(Bytes::copy_from_slice)
+            .collect();
+        tokio::spawn(async move {
+            for chunk in chunks {
+                tx.send(Ok(chunk)).await.unwrap();
+            }
+        });
+        let body = res.take_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, page.as_bytes());
+    }
+
+    #[test]
+    fn accept_quality_prefers_specific_ranges() {
+        let webp: Mime = "image/webp".parse().unwrap();
+        assert_eq!(accept_quality("image/webp", &webp), 1.0);
+        assert_eq!(
+            accept_quality("image/*;q=0.5, image/webp;q=0.8", &webp),
+            0.8
+        );
+        assert_eq!(accept_quality("image/webp;q=0.2, image/*", &webp), 0.2);
+        assert_eq!(accept_quality("text/html, image/*;q=0.4", &webp), 0.4);
+        assert_eq!(accept_quality("image/webp; charset=x; q=0.3", &webp), 0.3);
+    }
+
+    #[test]
+    fn accept_quality_ignores_wildcards_and_garbage() {
+        let webp: Mime = "image/webp".parse().unwrap();
+        assert_eq!(accept_quality("*/*", &webp), 0.0);
+        assert_eq!(accept_quality("text/*, image/png", &webp), 0.0);
+        assert_eq!(accept_quality("", &webp), 0.0);
+        assert_eq!(accept_quality("not a mime, image/webp;q=0.7", &webp), 0.7);
+        assert_eq!(accept_quality("image/webp;q=abc", &webp), 1.0);
+    }
+
+    fn lookup(name: &str) -> Option<&'static str> {
+        match name {
+            "TARGET" => Some("https://example.com/{CODE}"),
+            "CODE" => Some("302"),
+            "EMPTY" => Some(""),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn fill_placeholders_known_names() {
+        assert_eq!(fill_placeholders("{CODE}", lookup), "302");
+        assert_eq!(
+            fill_placeholders("to {TARGET} ({CODE})", lookup),
+            "to https://example.com/{CODE} (302)"
+        );
+        assert_eq!(fill_placeholders("a{EMPTY}b", lookup), "ab");
+        assert_eq!(
+            fill_placeholders("no placeholders", lookup),
+            "no placeholders"
+        );
+    }
+
+    #[test]
+    fn fill_placeholders_keeps_unknown_and_unclosed() {
+        assert_eq!(fill_placeholders("{OTHER} {CODE}", lookup), "{OTHER} 302");
+        assert_eq!(fill_placeholders("{{CODE}}", lookup), "{302}");
+        assert_eq!(fill_placeholders("{CODE", lookup), "{CODE");
+        assert_eq!(fill_placeholders("}{}{", lookup), "}{}{");
+    }
+
+    fn header(name: &str, value: &str) -> (HeaderName, String) {
+        (HeaderName::from_str(name).unwrap(), value.to_string())
+    }
+
+    #[test]
+    fn parse_headers_continues_values() {
+        assert_eq!(
+            parse_headers("X-To:{TARGET},Cache-Control:no-cache, no-store"),
+            Some(vec![
+                header("x-to", "{TARGET}"),
+                header("cache-control", "no-cache, no-store")
+            ])
+        );
+        assert_eq!(
+            parse_headers("Link:<a>; rel=x, <https://b>; rel=y, X-A: 1"),
+            Some(vec![
+                header("link", "<a>; rel=x, <https://b>; rel=y"),
+                header("x-a", "1")
+            ])
+        );
+        assert_eq!(parse_headers(""), Some(vec![]));
+    }
+
+    #[test]
+    fn parse_headers_rejects_ambiguous_and_invalid() {
+        assert_eq!(parse_headers("Link:<a>, https://b"), None);
+        assert_eq!(parse_headers("no-cache, no-store"), None);
+        assert_eq!(parse_headers("Bad Name:x"), None);
+        assert_eq!(parse_headers("X-A:a\r\nX-B: b"), None);
+    }
+
+    #[test]
+    fn fill_headers_skips_invalid_values() {
+        let headers = [header("x-path", "{PATH}"), header("x-code", "{CODE}")];
+        let filled = fill_headers("test", &headers, StatusCode::FOUND, |d| match d {
+            "PATH" => Some("/a\r\nSet-Cookie: x=1"),
+            "CODE" => Some("302"),
+            _ => None,
+        });
+        assert_eq!(filled.len(), 1);
+        assert_eq!(filled[0].0, "x-code");
+        assert_eq!(filled[0].1, "302");
+    }
+
+    #[test]
+    fn fill_headers_only_for_successful_responses() {
+        let headers = [header("x-to", "{TARGET}")];
+        let target = |d: &str| (d == "TARGET").then_some("https://example.com");
+        assert_eq!(
+            fill_headers("test", &headers, StatusCode::OK, target).len(),
+            1
+        );
+        for status in [StatusCode::NOT_FOUND, StatusCode::BAD_GATEWAY] {
+            assert!(fill_headers("test", &headers, status, target).is_empty());
+        }
+        // Without a target the placeholder stays as it is
+        let filled = fill_headers("test", &headers, StatusCode::OK, |_| None);
+        assert_eq!(filled[0].1, "{TARGET}");
+    }
+
+    #[test]
+    fn slow_start_ramps_linearly() {
+        let duration = Duration::from_secs(100);
+        let share = |secs: f32| SlowStart::share(Duration::from_secs_f32(secs), duration);
+        assert_eq!(share(0.0), 0.01);
+        assert_eq!(share(0.5), 0.01);
+        assert_eq!(share(25.0), 0.25);
+        assert_eq!(share(50.0), 0.5);
+        assert!(share(99.9) < 1.0);
+        assert_eq!(share(100.0), 1.0);
+        assert_eq!(share(1000.0), 1.0);
+    }
+
+    #[test]
+    fn parse_bytes_units() {
+        assert_eq!(parse_bytes("512"), Some(512));
+        assert_eq!(parse_bytes(" 10KB "), Some(10_000));
+        assert_eq!(parse_bytes("10 kb"), Some(10_000));
+        assert_eq!(parse_bytes("1MiB"), Some(1 << 20));
+        assert_eq!(parse_bytes("2GB"), Some(2_000_000_000));
+        assert_eq!(parse_bytes("0B"), Some(0));
+    }
+
+    #[test]
+    fn parse_bytes_rejects_garbage() {
+        assert_eq!(parse_bytes(""), None);
+        assert_eq!(parse_bytes("MB"), None);
+        assert_eq!(parse_bytes("10TB"), None);
+        assert_eq!(parse_bytes("-1"), None);
+        assert_eq!(parse_bytes("1.5MB"), None);
+        assert_eq!(parse_bytes("99999999999999999999GiB"), None);
+        assert_eq!(parse_bytes("18446744073709551615GiB"), None);
+    }
 }
@@ -0,0 +1,326 @@
+use std::time::Instant;
+
+use http_body_util::LengthLimitError;
+use salvo::http::{header, HeaderValue};
+use salvo::prelude::*;
+use tracing::{event, field, info_span, warn, Instrument, Level};
+
+use crate::rewrite::OriginalPath;
+
+/// Extracts the trace id from a W3C `traceparent` header (`version-traceid-parentid-flags`).
+fn trace_id(traceparent: &str) -> Option<&str> {
+    let trace_id = traceparent.split('-').nth(1)?;
+    let valid = trace_id.len() == 32
+        && trace_id.bytes().all(|b| b.is_ascii_hexdigit())
+        && trace_id.bytes().any(|b| b != b'0');
+    valid.then_some(trace_id)
+}
+
+const REQUEST_ID_HEADER: &str = "X-Request-ID";
+
+/// Returns the client's request id if it's sane enough to log and echo, or a new random one.
+fn request_id(req: &Request) -> String {
+    let incoming = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|d| d.to_str().ok())
+        .filter(|d| !d.is_empty() && d.len() <= 128 && d.bytes().all(|b| b.is_ascii_graphic()));
+    match incoming {
+        Some(d) => d.to_string(),
+        None => format!("{:032x}", fastrand::u128(..)),
+    }
+}
+
+/// How a handler wants its requests logged. Put into the depot by the handler and read back
+/// once it's done.
+pub(crate) struct AccessLog {
+    /// `None` for not at all.
+    pub(crate) level: Option<Level>,
+    /// Fraction of successful requests that get logged.
+    pub(crate) sample_rate: f32,
+}
+
+/// Wraps every request in an `http_request` span and logs its outcome once handled.
+pub(crate) struct RequestTraceMiddleware {
+    /// Used for requests that no handler took.
+    pub(crate) sample_rate: f32,
+}
+
+#[async_trait]
+impl Handler for RequestTraceMiddleware {
+    async fn handle(
+        &self,
+        req: &mut Request,
+        depot: &mut Depot,
+        res: &mut Response,
+        ctrl: &mut FlowCtrl,
+    ) {
+        let span = info_span!(
+            "http_request",
+            method = %req.method(),
+            path = %req.uri().path(),
+            original_path = field::Empty,
+            trace_id = field::Empty,
+            request_id = field::Empty,
+        );
+        // Routing, and with it the path rewrite, is done by the time service hoops run
+        if let Some(OriginalPath(d)) = req.extensions().get::<OriginalPath>() {
+            span.record("original_path", d.as_str());
+        }
+        let id = request_id(req);
+        span.record("request_id", id.as_str());
+        // Set on the request too, so proxies forward a generated id upstream
+        let header_value = HeaderValue::from_str(&id).ok();
+        if let Some(d) = &header_value {
+            req.headers_mut().insert(REQUEST_ID_HEADER, d.clone());
+        }
+        depot.insert("request_id", id);
+        if let Some(traceparent) = req.headers().get("traceparent") {
+            if let Some(d) = traceparent.to_str().ok().and_then(trace_id) {
+                span.record("trace_id", d);
+            }
+        }
+        async {
+            let start = Instant::now();
+            ctrl.call_next(req, depot, res).await;
+            // Only now, handlers may replace all response headers
+            if let Some(d) = header_value {
+                res.headers_mut().insert(REQUEST_ID_HEADER, d);
+            }
+            let (level, sample_rate) = depot
+                .obtain::<AccessLog>()
+                .map_or((Some(Level::INFO), self.sample_rate), |d| {
+                    (d.level, d.sample_rate)
+                });
+            let status = res.status_code.unwrap_or(StatusCode::OK);
+            let elapsed_ms = start.elapsed().as_millis() as u64;
+            // Errors are always logged, only successful requests are sampled
+            let sampled = if sample_rate >= 1.0 {
+                None
+            } else if status.is_success() || status.is_redirection() {
+                if fastrand::f32() >= sample_rate {
+                    return;
+                }
+                Some(true)
+            } else {
+                Some(false)
+            };
+            // The level of an event has to be known at compile time
+            macro_rules! completed {
+                ($level:expr) => {
+                    event!($level, status = %status, elapsed_ms, sampled, "request completed")
+                };
+            }
+            match level {
+                Some(Level::TRACE) => completed!(Level::TRACE),
+                Some(Level::DEBUG) => completed!(Level::DEBUG),
+                Some(Level::INFO) => completed!(Level::INFO),
+                Some(Level::WARN) => completed!(Level::WARN),
+                Some(Level::ERROR) => completed!(Level::ERROR),
+                None => {}
+            }
+        }
+        .instrument(span)
+        .await;
+    }
+}
+
+/// Answers 413 for request bodies larger than the given number of bytes.
+///
+/// A `Content-Length` over the limit is rejected right away. Bodies without one are read
+/// up to the limit here, so handlers further down get the already buffered payload.
+pub(crate) struct RequestBodyLimit(pub(crate) u64);
+
+/// Whether reading the body failed because it was larger than the allowed size.
+pub(crate) fn is_too_large(e: &salvo::http::ParseError) -> bool {
+    matches!(e, salvo::http::ParseError::Other(d) if d.is::<LengthLimitError>())
+}
+
+#[async_trait]
+impl Handler for RequestBodyLimit {
+    async fn handle(
+        &self,
+        req: &mut Request,
+        depot: &mut Depot,
+        res: &mut Response,
+        ctrl: &mut FlowCtrl,
+    ) {
+        let limit = usize::try_from(self.0).unwrap_or(usize::MAX);
+        req.set_secure_max_size(limit);
+        let too_large = match req.headers().get(header::CONTENT_LENGTH) {
+            Some(d) => d
+                .to_str()
+                .ok()
+                .and_then(|d| d.parse::<u64>().ok())
+                .is_none_or(|d| d > self.0),
+            // Chunked HTTP/1.1 and HTTP/2 bodies without a length are read up to the limit
+            None => match req.payload_with_max_size(limit).await {
+                Ok(_) => false,
+                Err(e) if is_too_large(&e) => true,
+                Err(e) => {
+                    warn!("Failed to read request body to {}: {e}", req.uri().path());
+                    res.status_code(StatusCode::BAD_REQUEST);
+                    ctrl.skip_rest();
+                    return;
+                }
+            },
+        };
+        if too_large {
+            warn!(
+                "Rejecting request body to {} over the limit of {} bytes",
+                req.uri().path(),
+                self.0
+            );
+            res.status_code(StatusCode::PAYLOAD_TOO_LARGE);
+            ctrl.skip_rest();
+            return;
+        }
+        ctrl.call_next(req, depot, res).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use salvo::conn::{Acceptor, TcpListener};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::*;
+
+    #[test]
+    fn trace_id_from_traceparent() {
+        assert_eq!(
+            trace_id("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+            Some("4bf92f3577b34da6a3ce929d0e0e4736")
+        );
+        assert_eq!(
+            trace_id("00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01"),
+            Some("4BF92F3577B34DA6A3CE929D0E0E4736")
+        );
+    }
+
+    #[test]
+    fn trace_id_rejects_malformed() {
+        assert_eq!(trace_id(""), None);
+        assert_eq!(trace_id("00"), None);
+        assert_eq!(trace_id("00-4bf92f3577b34da6-00f067aa0ba902b7-01"), None);
+        assert_eq!(
+            trace_id("00-4bf92f3577b34da6a3ce929d0e0e473g-00f067aa0ba902b7-01"),
+            None
+        );
+        assert_eq!(
+            trace_id("00-00000000000000000000000000000000-00f067aa0ba902b7-01"),
+            None
+        );
+    }
+
+    fn request_with_id(id: &[u8]) -> Request {
+        let mut req = Request::new();
+        req.headers_mut()
+            .insert(REQUEST_ID_HEADER, HeaderValue::from_bytes(id).unwrap());
+        req
+    }
+
+    fn is_generated(id: &str) -> bool {
+        id.len() == 32 && id.bytes().all(|b| b.is_ascii_hexdigit())
+    }
+
+    #[test]
+    fn request_id_keeps_valid_ids() {
+        let id = "req-42_abc.DEF:~";
+        assert_eq!(request_id(&request_with_id(id.as_bytes())), id);
+        let longest = "a".repeat(128);
+        assert_eq!(request_id(&request_with_id(longest.as_bytes())), longest);
+    }
+
+    #[test]
+    fn request_id_replaces_invalid_ids() {
+        let oversized = "a".repeat(129);
+        for id in [
+            oversized.as_bytes(),
+            "".as_bytes(),
+            "with space".as_bytes(),
+            "caf\u{e9}".as_bytes(),
+            b"\xff\xfe",
+        ] {
+            let generated = request_id(&request_with_id(id));
+            assert!(is_generated(&generated), "{id:?} was kept as {generated}");
+        }
+        assert!(is_generated(&request_id(&Request::new())));
+    }
+
+    #[test]
+    fn generated_request_ids_differ() {
+        assert_ne!(request_id(&Request::new()), request_id(&Request::new()));
+    }
+
+    #[handler]
+    async fn echo(req: &mut Request, res: &mut Response) {
+        let body = req.payload().await.unwrap().clone();
+        res.write_body(body).unwrap();
+    }
+
+    /// Serves `echo` behind a 10 byte body limit.
+    async fn serve() -> SocketAddr {
+        let acceptor = TcpListener::new("127.0.0.1:0").bind().await;
+        let addr = acceptor.holdings()[0]
+            .local_addr
+            .clone()
+            .into_std()
+            .unwrap();
+        let router = Router::new().hoop(RequestBodyLimit(10)).post(echo);
+        tokio::spawn(Server::new(acceptor).serve(router));
+        addr
+    }
+
+    /// Posts `body` with a `Content-Length` or chunked, returning the raw response.
+    async fn post(addr: SocketAddr, body: &str, chunked: bool) -> String {
+        let framing = if chunked {
+            format!(
+                "Transfer-Encoding: chunked\r\n\r\n{:x}\r\n{body}\r\n0\r\n\r\n",
+                body.len()
+            )
+        } else {
+            format!("Content-Length: {}\r\n\r\n{body}", body.len())
+        };
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(
+                format!("POST / HTTP/1.1\r\nHost: a\r\nConnection: close\r\n{framing}").as_bytes(),
+            )
+            .await
+            .unwrap();
+        let mut read = vec![];
+        let mut buf = [0; 1024];
+        loop {
+            match stream.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => read.extend_from_slice(&buf[..n]),
+            }
+        }
+        String::from_utf8_lossy(&read).into_owned()
+    }
+
+    #[tokio::test]
+    async fn request_body_limit_passes_small_bodies() {
+        let addr = serve().await;
+        for chunked in [false, true] {
+            let response = post(addr, "0123456789", chunked).await;
+            assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+            assert!(response.ends_with("\r\n\r\n0123456789"), "{response}");
+        }
+    }
+
+    #[tokio::test]
+    async fn request_body_limit_rejects_large_bodies() {
+        let addr = serve().await;
+        for chunked in [false, true] {
+            let response = post(addr, "0123456789a", chunked).await;
+            assert!(response.starts_with("HTTP/1.1 413"), "{response}");
+        }
+    }
+}
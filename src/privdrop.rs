@@ -0,0 +1,31 @@
+use nix::unistd::{setgid, setuid, Group, User};
+use tracing::info;
+
+/// Switches the process to `spec` (`user:group`), group first so the user change can't lock
+/// us out of it.
+pub(crate) fn drop_to(spec: &str) -> Result<(), String> {
+    let Some((user_name, group_name)) = spec.split_once(':') else {
+        return Err(format!("\"{spec}\" is not in the form user:group"));
+    };
+    let user = match User::from_name(user_name) {
+        Ok(Some(d)) => d,
+        Ok(None) => return Err(format!("User \"{user_name}\" doesn't exist")),
+        Err(e) => return Err(format!("Failed to look up user \"{user_name}\": {e}")),
+    };
+    let group = match Group::from_name(group_name) {
+        Ok(Some(d)) => d,
+        Ok(None) => return Err(format!("Group \"{group_name}\" doesn't exist")),
+        Err(e) => return Err(format!("Failed to look up group \"{group_name}\": {e}")),
+    };
+    // Supplementary groups inherited from root would survive setgid otherwise
+    #[cfg(not(any(target_vendor = "apple", target_os = "redox", target_os = "haiku")))]
+    nix::unistd::setgroups(&[group.gid])
+        .map_err(|e| format!("Failed to drop supplementary groups: {e}"))?;
+    setgid(group.gid).map_err(|e| format!("Failed to switch to group \"{group_name}\": {e}"))?;
+    setuid(user.uid).map_err(|e| format!("Failed to switch to user \"{user_name}\": {e}"))?;
+    info!(
+        "Dropped privileges to {user_name}:{group_name} (uid={}, gid={})",
+        user.uid, group.gid
+    );
+    Ok(())
+}
@@ -0,0 +1,435 @@
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, LazyLock, Mutex, OnceLock},
+    time::Duration,
+};
+
+use futures_util::StreamExt;
+use reqwest::{redirect::Policy, Client};
+use salvo::{
+    http::{
+        header::{
+            AUTHORIZATION, CACHE_CONTROL, CONNECTION, COOKIE, HOST, PROXY_AUTHENTICATE,
+            PROXY_AUTHORIZATION, SET_COOKIE, TE, TRAILER, TRANSFER_ENCODING, UPGRADE,
+        },
+        HeaderMap, HeaderName, Method,
+    },
+    hyper::body::Bytes,
+    prelude::*,
+};
+use tokio::{
+    sync::{oneshot, watch},
+    time::timeout,
+};
+use tracing::{debug, error, warn};
+
+use crate::middleware::is_too_large;
+
+static CLIENT: OnceLock<Client> = OnceLock::new();
+
+/// Sets up the client shared by all proxies, a dead upstream is answered with 504 after the
+/// timeouts.
+pub(crate) fn init_client(connect_timeout: Duration, read_timeout: Duration) {
+    // Upstream redirects are handed to the client as they are
+    let client = Client::builder()
+        .redirect(Policy::none())
+        .connect_timeout(connect_timeout)
+        .read_timeout(read_timeout)
+        .build()
+        .expect("Failed to build HTTP client");
+    let _ = CLIENT.set(client);
+}
+
+fn client() -> &'static Client {
+    CLIENT
+        .get()
+        .expect("Proxy client used before init_client()")
+}
+
+/// Status to answer with when the upstream request failed.
+fn upstream_error(e: &reqwest::Error) -> StatusCode {
+    if e.is_timeout() {
+        StatusCode::GATEWAY_TIMEOUT
+    } else {
+        StatusCode::BAD_GATEWAY
+    }
+}
+
+/// A fully buffered upstream response, shared by coalesced requests.
+struct CachedResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+/// Outcome of a coalesced upstream request, as seen by the requests that joined it.
+#[derive(Clone)]
+enum Shared {
+    Running,
+    Done(Result<Arc<CachedResponse>, StatusCode>),
+    /// Only meant for the first client, the others have to send their own request.
+    Private,
+}
+
+/// Coalesced upstream requests currently running, keyed by handler, method and target.
+static IN_FLIGHT: LazyLock<Mutex<HashMap<String, watch::Receiver<Shared>>>> =
+    LazyLock::new(Mutex::default);
+
+/// Cap for buffering a coalesced response when the handler has no `MAX_RESPONSE_BODY`.
+const DEFAULT_COALESCE_LIMIT: u64 = 10 << 20;
+
+/// Removes a coalesced request from `IN_FLIGHT` however it ends, also if it panics.
+struct InFlightGuard(String);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        IN_FLIGHT.lock().unwrap().remove(&self.0);
+    }
+}
+
+/// Whether `Cache-Control` allows handing the response to more than one client.
+fn is_shareable(headers: &HeaderMap) -> bool {
+    !headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|d| d.to_str().ok())
+        .flat_map(|d| d.split(','))
+        .map(|d| d.split('=').next().unwrap_or_default().trim())
+        .any(|d| d.eq_ignore_ascii_case("private") || d.eq_ignore_ascii_case("no-store"))
+}
+
+/// Headers that only apply to a single connection and must not be forwarded.
+const HOP_BY_HOP: [HeaderName; 7] = [
+    CONNECTION,
+    PROXY_AUTHENTICATE,
+    PROXY_AUTHORIZATION,
+    TE,
+    TRAILER,
+    TRANSFER_ENCODING,
+    UPGRADE,
+];
+
+fn filter_headers(headers: &HeaderMap, allowed: Option<&[String]>) -> HeaderMap {
+    let mut filtered = HeaderMap::new();
+    for (key, value) in headers.iter() {
+        if HOP_BY_HOP.contains(key) || key == HOST {
+            continue;
+        }
+        if let Some(allowed) = allowed {
+            if !allowed.iter().any(|d| d == key.as_str()) {
+                continue;
+            }
+        }
+        filtered.append(key, value.clone());
+    }
+    filtered
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ProxyOptions {
+    /// Lowercase names of the request headers sent upstream, all of them when `None`.
+    pub(crate) forward_headers: Option<Vec<String>>,
+    /// Upstream responses with larger bodies are cut off.
+    pub(crate) max_response_body: Option<u64>,
+    /// Concurrent GET and HEAD requests for the same target share one upstream request.
+    pub(crate) coalesce: bool,
+    /// Longest a coalesced request may take, including reading the whole body.
+    pub(crate) coalesce_timeout: Duration,
+    /// Request bodies are read without a cap when `None`, salvo's 64KiB default doesn't apply.
+    pub(crate) max_request_body: Option<u64>,
+}
+
+/// Forwards the request to `target` and streams the upstream response back.
+pub(crate) async fn forward(
+    name: &str,
+    target: &str,
+    options: &ProxyOptions,
+    req: &mut Request,
+    res: &mut Response,
+) {
+    let headers = filter_headers(req.headers(), options.forward_headers.as_deref());
+    let method = req.method().clone();
+    let limit = options
+        .max_request_body
+        .map_or(usize::MAX, |d| usize::try_from(d).unwrap_or(usize::MAX));
+    let body = match req.payload_with_max_size(limit).await {
+        Ok(d) => d.clone(),
+        Err(e) if is_too_large(&e) => {
+            warn!("Request body for {target} exceeds the limit of {limit} bytes");
+            res.status_code(StatusCode::PAYLOAD_TOO_LARGE);
+            return;
+        }
+        Err(e) => {
+            error!("Failed to read request body for {target}: {e}");
+            res.status_code(StatusCode::BAD_REQUEST);
+            return;
+        }
+    };
+    // Credentialed responses are private to the client, sharing them would leak them
+    let credentialed = headers.contains_key(AUTHORIZATION) || headers.contains_key(COOKIE);
+    if options.coalesce && !credentialed && (method == Method::GET || method == Method::HEAD) {
+        let request = client()
+            .request(method.clone(), target)
+            .headers(headers.clone())
+            .body(body.clone());
+        match coalesced(
+            format!("{name} {method} {target}"),
+            request,
+            target,
+            options,
+        )
+        .await
+        {
+            Some(Ok(d)) => {
+                res.status_code(d.status);
+                res.set_headers(filter_headers(&d.headers, None));
+                res.body(d.body.clone());
+                return;
+            }
+            Some(Err(status)) => {
+                res.status_code(status);
+                return;
+            }
+            None => debug!("Response of {target} is private, requesting it again for {name}"),
+        }
+    }
+    let upstream = match client()
+        .request(method, target)
+        .headers(headers)
+        .body(body)
+        .send()
+        .await
+    {
+        Ok(d) => d,
+        Err(e) => {
+            error!("Upstream request to {target} failed: {e}");
+            res.status_code(upstream_error(&e));
+            return;
+        }
+    };
+    let Some(limit) = options.max_response_body else {
+        res.status_code(upstream.status());
+        res.set_headers(filter_headers(upstream.headers(), None));
+        res.stream(upstream.bytes_stream());
+        return;
+    };
+    if upstream.content_length().is_some_and(|d| d > limit) {
+        warn!("Response of {target} for handler {name} exceeds the {limit} byte limit");
+        res.status_code(StatusCode::BAD_GATEWAY);
+        return;
+    }
+    res.status_code(upstream.status());
+    res.set_headers(filter_headers(upstream.headers(), None));
+    // Without a (trustworthy) length, count while streaming. Headers are already out by the
+    // time the limit is hit, so the connection is aborted instead.
+    let name = name.to_string();
+    let target = target.to_string();
+    let mut seen: u64 = 0;
+    res.stream(upstream.bytes_stream().map(move |chunk| {
+        let chunk = chunk.map_err(io::Error::other)?;
+        seen += chunk.len() as u64;
+        if seen > limit {
+            warn!("Response of {target} for handler {name} truncated at the {limit} byte limit");
+            return Err(io::Error::other("response body limit exceeded"));
+        }
+        Ok(chunk)
+    }));
+}
+
+/// Sends `request` unless an identical one is already running, and waits for its outcome.
+///
+/// `None` if the response turned out to be private to the client that sent the request.
+async fn coalesced(
+    key: String,
+    request: reqwest::RequestBuilder,
+    target: &str,
+    options: &ProxyOptions,
+) -> Option<Result<Arc<CachedResponse>, StatusCode>> {
+    let (mut rx, first_rx) = {
+        let mut in_flight = IN_FLIGHT.lock().unwrap();
+        match in_flight.get(&key) {
+            Some(rx) => {
+                debug!("Coalescing {key}");
+                (rx.clone(), None)
+            }
+            None => {
+                let (tx, rx) = watch::channel(Shared::Running);
+                let (first_tx, first_rx) = oneshot::channel();
+                in_flight.insert(key.clone(), rx.clone());
+                // Runs detached so waiters aren't affected if the first client goes away
+                let limit = options.max_response_body.unwrap_or(DEFAULT_COALESCE_LIMIT);
+                let duration = options.coalesce_timeout;
+                let target = target.to_string();
+                tokio::spawn(async move {
+                    let guard = InFlightGuard(key);
+                    let result =
+                        match timeout(duration, fetch_buffered(request, &target, limit)).await {
+                            Ok(d) => d.map(Arc::new),
+                            Err(_) => {
+                                error!("Upstream request to {target} timed out after {duration:?}");
+                                Err(StatusCode::GATEWAY_TIMEOUT)
+                            }
+                        };
+                    let shared = match &result {
+                        Ok(d) if !is_shareable(&d.headers) => Shared::Private,
+                        d => Shared::Done(d.clone()),
+                    };
+                    // Requests arriving from now on start a new upstream request
+                    drop(guard);
+                    let _ = tx.send(shared);
+                    let _ = first_tx.send(result);
+                });
+                (rx, Some(first_rx))
+            }
+        }
+    };
+    if let Some(first_rx) = first_rx {
+        return Some(first_rx.await.unwrap_or(Err(StatusCode::BAD_GATEWAY)));
+    }
+    let shared = match rx.wait_for(|d| !matches!(d, Shared::Running)).await {
+        Ok(d) => d.clone(),
+        Err(_) => Shared::Done(Err(StatusCode::BAD_GATEWAY)),
+    };
+    match shared {
+        Shared::Done(d) => Some(d),
+        Shared::Private | Shared::Running => None,
+    }
+}
+
+/// Sends `request` and reads the whole response, failing with the status to answer with.
+async fn fetch_buffered(
+    request: reqwest::RequestBuilder,
+    target: &str,
+    limit: u64,
+) -> Result<CachedResponse, StatusCode> {
+    let upstream = request.send().await.map_err(|e| {
+        error!("Upstream request to {target} failed: {e}");
+        upstream_error(&e)
+    })?;
+    let status = upstream.status();
+    let mut headers = upstream.headers().clone();
+    // Cookies are set for a single client
+    headers.remove(SET_COOKIE);
+    let mut body = Vec::new();
+    let mut stream = upstream.bytes_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| {
+            error!("Reading the response of {target} failed: {e}");
+            upstream_error(&e)
+        })?;
+        body.extend_from_slice(&chunk);
+        if body.len() as u64 > limit {
+            warn!("Response of {target} exceeds the {limit} byte limit, not sharing it");
+            return Err(StatusCode::BAD_GATEWAY);
+        }
+    }
+    Ok(CachedResponse {
+        status,
+        headers,
+        body: body.into(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use salvo::http::HeaderValue;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        time::sleep,
+    };
+
+    use super::*;
+
+    /// Serves `response` to every connection after a short delay, or never answers without one.
+    async fn upstream(response: Option<&'static [u8]>) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counter = connections.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut buf = [0; 4096];
+                    let _ = stream.read(&mut buf).await;
+                    let Some(response) = response else {
+                        // Keeps the connection open without ever answering
+                        sleep(Duration::from_secs(60)).await;
+                        return;
+                    };
+                    sleep(Duration::from_millis(200)).await;
+                    let _ = stream.write_all(response).await;
+                });
+            }
+        });
+        (url, connections)
+    }
+
+    fn options() -> ProxyOptions {
+        ProxyOptions {
+            forward_headers: None,
+            max_response_body: None,
+            coalesce: true,
+            coalesce_timeout: Duration::from_millis(300),
+            max_request_body: None,
+        }
+    }
+
+    #[test]
+    fn private_responses_are_not_shareable() {
+        let mut headers = HeaderMap::new();
+        assert!(is_shareable(&headers));
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=60"));
+        assert!(is_shareable(&headers));
+        headers.insert(
+            CACHE_CONTROL,
+            HeaderValue::from_static("max-age=60, No-Store"),
+        );
+        assert!(!is_shareable(&headers));
+        headers.insert(
+            CACHE_CONTROL,
+            HeaderValue::from_static("private=\"x-user\""),
+        );
+        assert!(!is_shareable(&headers));
+    }
+
+    #[tokio::test]
+    async fn stalled_upstream_times_out_for_everyone() {
+        init_client(Duration::from_secs(1), Duration::from_secs(30));
+        let (url, connections) = upstream(None).await;
+        let key = format!("stalled GET {url}");
+        let request = || client().get(&url);
+        let options = options();
+        let (first, second) = tokio::join!(
+            coalesced(key.clone(), request(), &url, &options),
+            coalesced(key.clone(), request(), &url, &options)
+        );
+        assert!(matches!(first, Some(Err(StatusCode::GATEWAY_TIMEOUT))));
+        assert!(matches!(second, Some(Err(StatusCode::GATEWAY_TIMEOUT))));
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+        assert!(!IN_FLIGHT.lock().unwrap().contains_key(&key));
+    }
+
+    #[tokio::test]
+    async fn private_response_only_goes_to_the_first_client() {
+        init_client(Duration::from_secs(1), Duration::from_secs(30));
+        let (url, _) = upstream(Some(
+            b"HTTP/1.1 200 OK\r\nCache-Control: private\r\nContent-Length: 2\r\n\r\nhi",
+        ))
+        .await;
+        let key = format!("private GET {url}");
+        let request = || client().get(&url);
+        let options = options();
+        let (first, second) = tokio::join!(
+            coalesced(key.clone(), request(), &url, &options),
+            coalesced(key.clone(), request(), &url, &options)
+        );
+        assert_eq!(first.unwrap().unwrap().body, "hi");
+        assert!(second.is_none());
+        assert!(!IN_FLIGHT.lock().unwrap().contains_key(&key));
+    }
+}
@@ -0,0 +1,183 @@
+use regex::Regex;
+use salvo::{
+    http::uri::{PathAndQuery, Uri},
+    prelude::*,
+    routing::{Filter, PathState},
+};
+use tracing::{debug, warn};
+
+/// Rewrites the request path before any route is matched.
+///
+/// Service hoops only run once routing is done, so this is a filter on the root router
+/// instead, where both the URI and salvo's matching state can still be replaced. The steps
+/// are applied in field order.
+#[derive(Debug, Clone)]
+pub(crate) struct PathRewrite {
+    /// Regex and replacement, as in `s|regex|replacement|`.
+    pub(crate) pattern: Option<(Regex, String)>,
+    pub(crate) strip_prefix: Option<String>,
+    pub(crate) add_prefix: Option<String>,
+}
+
+impl PathRewrite {
+    pub(crate) fn is_noop(&self) -> bool {
+        self.pattern.is_none() && self.strip_prefix.is_none() && self.add_prefix.is_none()
+    }
+
+    /// Human readable list of the steps, used by `--print-routes`.
+    pub(crate) fn describe(&self) -> String {
+        let mut steps = vec![];
+        if let Some((re, replacement)) = &self.pattern {
+            steps.push(format!("replace {re} with {replacement}"));
+        }
+        if let Some(prefix) = &self.strip_prefix {
+            steps.push(format!("strip {prefix}"));
+        }
+        if let Some(prefix) = &self.add_prefix {
+            steps.push(format!("add {prefix}"));
+        }
+        steps.join(", then ")
+    }
+
+    fn rewrite(&self, path: &str) -> String {
+        let mut path = path.to_string();
+        if let Some((re, replacement)) = &self.pattern {
+            path = re.replace(&path, replacement.as_str()).into_owned();
+        }
+        if let Some(prefix) = &self.strip_prefix {
+            // Only strip whole segments, `/app` must not turn `/apples` into `/les`
+            if let Some(rest) = path.strip_prefix(prefix.as_str()) {
+                if rest.is_empty() || rest.starts_with('/') {
+                    path = rest.to_string();
+                }
+            }
+        }
+        if let Some(prefix) = &self.add_prefix {
+            path = format!("{prefix}{path}");
+        }
+        if !path.starts_with('/') {
+            path.insert(0, '/');
+        }
+        path
+    }
+}
+
+/// Drops trailing slashes, since prefixes are matched and added as whole segments.
+/// `None` if nothing is left.
+pub(crate) fn parse_prefix(value: &str) -> Option<String> {
+    let prefix = value.trim().trim_end_matches('/');
+    (!prefix.is_empty()).then(|| prefix.to_string())
+}
+
+/// The path a request had before it was rewritten, set as a request extension.
+#[derive(Debug, Clone)]
+pub(crate) struct OriginalPath(pub(crate) String);
+
+/// Parses sed-like `s|regex|replacement|`, where any character after the `s` can be the delimiter.
+pub(crate) fn parse_substitution(value: &str) -> Option<(Regex, String)> {
+    let rest = value.strip_prefix('s')?;
+    let delimiter = rest.chars().next()?;
+    let parts: Vec<&str> = rest[delimiter.len_utf8()..].split(delimiter).collect();
+    let [pattern, replacement, ""] = parts[..] else {
+        return None;
+    };
+    Some((Regex::new(pattern).ok()?, replacement.to_string()))
+}
+
+#[async_trait]
+impl Filter for PathRewrite {
+    async fn filter(&self, req: &mut Request, path: &mut PathState) -> bool {
+        let original = req.uri().path().to_string();
+        let rewritten = self.rewrite(&original);
+        if rewritten == original {
+            return true;
+        }
+        let path_and_query = match req.uri().query() {
+            Some(query) => format!("{rewritten}?{query}"),
+            None => rewritten.clone(),
+        };
+        let mut parts = req.uri().clone().into_parts();
+        parts.path_and_query = match PathAndQuery::try_from(path_and_query) {
+            Ok(d) => Some(d),
+            Err(e) => {
+                warn!("Not rewriting {original}, {rewritten} isn't a valid path: {e}");
+                return true;
+            }
+        };
+        match Uri::from_parts(parts) {
+            Ok(uri) => {
+                debug!("Rewrote {original} to {rewritten}");
+                *req.uri_mut() = uri;
+                *path = PathState::new(&rewritten);
+                req.extensions_mut().insert(OriginalPath(original));
+            }
+            Err(e) => warn!("Not rewriting {original}: {e}"),
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewrite(pattern: Option<&str>, strip: Option<&str>, add: Option<&str>) -> PathRewrite {
+        PathRewrite {
+            pattern: pattern.map(|d| parse_substitution(d).unwrap()),
+            strip_prefix: strip.and_then(parse_prefix),
+            add_prefix: add.and_then(parse_prefix),
+        }
+    }
+
+    #[test]
+    fn substitution_delimiters() {
+        let (re, replacement) = parse_substitution("s|^/old/(.*)|/new/$1|").unwrap();
+        assert_eq!(re.as_str(), "^/old/(.*)");
+        assert_eq!(replacement, "/new/$1");
+        let (re, replacement) = parse_substitution("s#/a#/b#").unwrap();
+        assert_eq!(re.as_str(), "/a");
+        assert_eq!(replacement, "/b");
+        assert!(parse_substitution("s||x|").is_some());
+    }
+
+    #[test]
+    fn substitution_rejects_malformed() {
+        assert!(parse_substitution("").is_none());
+        assert!(parse_substitution("s").is_none());
+        assert!(parse_substitution("x|a|b|").is_none());
+        assert!(parse_substitution("s|a|b").is_none());
+        assert!(parse_substitution("s|a|b|c|").is_none());
+        assert!(parse_substitution("s|(|b|").is_none());
+    }
+
+    #[test]
+    fn prefixes_drop_trailing_slashes() {
+        assert_eq!(parse_prefix("/app/").as_deref(), Some("/app"));
+        assert_eq!(parse_prefix("/app").as_deref(), Some("/app"));
+        assert_eq!(parse_prefix("/"), None);
+        assert_eq!(parse_prefix(""), None);
+    }
+
+    #[test]
+    fn strip_only_whole_segments() {
+        let r = rewrite(None, Some("/app/"), None);
+        assert_eq!(r.rewrite("/app/x"), "/x");
+        assert_eq!(r.rewrite("/app"), "/");
+        assert_eq!(r.rewrite("/apples"), "/apples");
+        assert_eq!(r.rewrite("/other/app/x"), "/other/app/x");
+    }
+
+    #[test]
+    fn steps_apply_in_order() {
+        let r = rewrite(Some("s|^/old|/app|"), Some("/app"), Some("/v2/"));
+        assert_eq!(r.rewrite("/old/x"), "/v2/x");
+        assert_eq!(r.rewrite("/y"), "/v2/y");
+        assert!(rewrite(None, None, None).is_noop());
+    }
+
+    #[test]
+    fn result_is_absolute() {
+        let r = rewrite(Some("s|^/||"), None, None);
+        assert_eq!(r.rewrite("/x"), "/x");
+    }
+}